@@ -0,0 +1,447 @@
+use std::io::{self, ErrorKind, Read, Write};
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+use termwiz::caps::{Capabilities, ProbeHints};
+use termwiz::terminal::UnixTerminal;
+
+use crate::control;
+
+/// How long to wait for a server started in the background to listen.
+const START_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How long a client may take to send its hello after connecting.
+const HELLO_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// A hello is a few dozen bytes, and so are the lines sent after it; anything much longer is
+/// not from `sudare`.
+const MAX_LINE_SIZE: usize = 4096;
+
+/// Path of the socket clients attach to the session running `procfile_hash` through, next
+/// to its control socket.
+pub fn socket_path(procfile_hash: &str) -> PathBuf {
+    control::socket_path(procfile_hash).with_extension("attach.sock")
+}
+
+/// Sent by a client along with its terminal, to probe its capabilities like at home.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Hello {
+    term: Option<String>,
+    colorterm: Option<String>,
+}
+
+/// Why the server let a client go.
+pub enum Release {
+    /// The processes keep running in the background.
+    Detached,
+    /// The session is over.
+    Exited,
+}
+
+impl Release {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Release::Detached => "detached",
+            Release::Exited => "exited",
+        }
+    }
+}
+
+/// Accepts clients on the attach socket, without blocking, from the main loop.
+pub struct Listener {
+    path: PathBuf,
+    listener: UnixListener,
+    pending: Vec<Pending>,
+}
+
+impl Listener {
+    pub fn bind(path: &Path) -> io::Result<Self> {
+        Ok(Self {
+            path: path.to_path_buf(),
+            listener: control::bind(path)?,
+            pending: Vec::new(),
+        })
+    }
+
+    /// A client that finished its hello since the last call, with its terminal.
+    pub fn accept(&mut self) -> io::Result<Option<Client>> {
+        loop {
+            match self.listener.accept() {
+                Ok((stream, _)) => {
+                    stream.set_nonblocking(true)?;
+                    self.pending.push(Pending {
+                        stream,
+                        buffer: Vec::new(),
+                        fds: Vec::new(),
+                        connected_at: Instant::now(),
+                    });
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) => return Err(e),
+            }
+        }
+
+        let mut i = 0;
+        while i < self.pending.len() {
+            match self.pending[i].read() {
+                Ok(true) => return self.pending.remove(i).into_client().map(Some),
+                Ok(false) if self.pending[i].connected_at.elapsed() < HELLO_TIMEOUT => i += 1,
+                Ok(false) => {
+                    log::error!("attach error: the client sent no hello in time");
+                    self.pending.remove(i);
+                }
+                Err(e) => {
+                    log::error!("attach error: {}", e);
+                    self.pending.remove(i);
+                }
+            }
+        }
+        Ok(None)
+    }
+}
+
+/// A client that connected, until its hello is complete.
+struct Pending {
+    stream: UnixStream,
+    buffer: Vec<u8>,
+    fds: Vec<OwnedFd>,
+    connected_at: Instant,
+}
+
+impl Pending {
+    /// Reads what the client sent so far. Returns whether its hello is complete.
+    fn read(&mut self) -> io::Result<bool> {
+        let mut buf = [0; 4096];
+        while !self.buffer.contains(&b'\n') {
+            if self.buffer.len() > MAX_LINE_SIZE {
+                return Err(io::Error::new(
+                    ErrorKind::InvalidData,
+                    format!("hello longer than {} bytes", MAX_LINE_SIZE),
+                ));
+            }
+            match recv_fds(&self.stream, &mut buf) {
+                Ok((0, _)) => return Err(ErrorKind::UnexpectedEof.into()),
+                Ok((n, fds)) => {
+                    self.buffer.extend_from_slice(&buf[..n]);
+                    self.fds.extend(fds);
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => return Ok(false),
+                Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(true)
+    }
+
+    fn into_client(self) -> io::Result<Client> {
+        let end = self.buffer.iter().position(|b| *b == b'\n');
+        let hello = serde_json::from_slice(&self.buffer[..end.unwrap_or(self.buffer.len())])?;
+        let [input, output]: [OwnedFd; 2] = self
+            .fds
+            .try_into()
+            .map_err(|_| io::Error::new(ErrorKind::InvalidData, "the client sent no terminal"))?;
+        Ok(Client {
+            stream: self.stream,
+            buffer: Vec::new(),
+            input,
+            output,
+            hello,
+        })
+    }
+}
+
+impl Drop for Listener {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+/// A client attached to the server, whose terminal the server draws on.
+pub struct Client {
+    stream: UnixStream,
+    /// What the client sent after its hello, until a whole line arrives.
+    buffer: Vec<u8>,
+    input: OwnedFd,
+    output: OwnedFd,
+    hello: Hello,
+}
+
+impl Client {
+    /// The terminal of the client, as if the server ran in it.
+    pub fn terminal(&self) -> termwiz::Result<UnixTerminal> {
+        let hints = ProbeHints::new_from_env()
+            .term(self.hello.term.clone())
+            .colorterm(self.hello.colorterm.clone())
+            .mouse_reporting(Some(false));
+        let caps = Capabilities::new_with_hints(hints)?;
+        UnixTerminal::new_with(caps, &self.input, &self.output)
+    }
+
+    /// Reads what the client sent. Returns the new size of its terminal when it was resized,
+    /// and fails once the client is gone.
+    pub fn poll(&mut self) -> io::Result<Option<(usize, usize)>> {
+        let mut buf = [0; 64];
+        loop {
+            match self.stream.read(&mut buf) {
+                Ok(0) => return Err(ErrorKind::UnexpectedEof.into()),
+                Ok(n) => self.buffer.extend_from_slice(&buf[..n]),
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+            if self.buffer.len() > MAX_LINE_SIZE {
+                return Err(io::Error::new(
+                    ErrorKind::InvalidData,
+                    format!("line longer than {} bytes", MAX_LINE_SIZE),
+                ));
+            }
+        }
+        let mut size = None;
+        while let Some(end) = self.buffer.iter().position(|b| *b == b'\n') {
+            let line = self.buffer.drain(..=end).collect::<Vec<_>>();
+            match parse_resize(&line) {
+                Some(v) => size = Some(v),
+                None => log::error!(
+                    "attach error: unexpected `{}`",
+                    String::from_utf8_lossy(&line).trim_end()
+                ),
+            }
+        }
+        Ok(size)
+    }
+
+    /// Lets the client exit, telling it why.
+    pub fn release(self, release: Release) {
+        let _ = self.stream.set_nonblocking(false);
+        let _ = (&self.stream).write_all(format!("{}\n", release.as_str()).as_bytes());
+    }
+}
+
+/// Size of a terminal from a `resize COLS ROWS` line.
+fn parse_resize(line: &[u8]) -> Option<(usize, usize)> {
+    let line = std::str::from_utf8(line).ok()?;
+    match line.split_whitespace().collect::<Vec<_>>().as_slice() {
+        ["resize", cols, rows] => match (cols.parse(), rows.parse()) {
+            (Ok(cols), Ok(rows)) if cols > 0 && rows > 0 => Some((cols, rows)),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+/// Whether a server is listening at `path`.
+pub fn is_running(path: &Path) -> bool {
+    control::connect(path).is_ok()
+}
+
+/// Starts `sudare server` with `args` in the background, in a session of its own so that it
+/// outlives the terminal, and waits for it to listen at `path`.
+pub fn start_server(path: &Path, args: &[String]) -> io::Result<()> {
+    let mut command = Command::new(std::env::current_exe()?);
+    command
+        .arg("server")
+        .args(args)
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null());
+    unsafe {
+        command.pre_exec(|| {
+            libc::setsid();
+            Ok(())
+        });
+    }
+    let mut child = command.spawn()?;
+    let started_at = Instant::now();
+    while !is_running(path) {
+        if let Some(status) = child.try_wait()? {
+            return Err(io::Error::other(format!(
+                "the server exited with {}; run `sudare server` to see why",
+                status
+            )));
+        }
+        if started_at.elapsed() > START_TIMEOUT {
+            return Err(io::Error::new(
+                ErrorKind::TimedOut,
+                "the server did not start in time",
+            ));
+        }
+        sleep(Duration::from_millis(50));
+    }
+    Ok(())
+}
+
+static RESIZED: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn on_resize(_: libc::c_int) {
+    RESIZED.store(true, Ordering::SeqCst);
+}
+
+/// Size of the terminal on stdout, as `resize COLS ROWS`.
+fn resize_line() -> io::Result<String> {
+    let mut size: libc::winsize = unsafe { std::mem::zeroed() };
+    if unsafe { libc::ioctl(1, libc::TIOCGWINSZ, &mut size) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(format!("resize {} {}\n", size.ws_col, size.ws_row))
+}
+
+/// Puts the terminal back the way it was before attaching when dropped, whatever the server
+/// left it in, e.g. when it crashed while drawing on it.
+struct SavedTerminal(libc::termios);
+
+impl SavedTerminal {
+    fn save() -> io::Result<Self> {
+        let mut termios: libc::termios = unsafe { std::mem::zeroed() };
+        if unsafe { libc::tcgetattr(0, &mut termios) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Self(termios))
+    }
+}
+
+impl Drop for SavedTerminal {
+    fn drop(&mut self) {
+        unsafe {
+            libc::tcsetattr(0, libc::TCSADRAIN, &self.0);
+        }
+        // Leave the alternate screen and show the cursor.
+        let mut stdout = io::stdout();
+        let _ = stdout.write_all(b"\x1b[?1049l\x1b[?25h");
+        let _ = stdout.flush();
+    }
+}
+
+/// Hands the terminal over to the server at `path`, and waits until the server lets it go.
+pub fn attach(path: &Path) -> io::Result<Release> {
+    if unsafe { libc::isatty(0) == 0 || libc::isatty(1) == 0 } {
+        return Err(io::Error::other(
+            "stdin and stdout must be a terminal to attach",
+        ));
+    }
+    let mut stream = control::connect(path)?;
+    let hello = Hello {
+        term: std::env::var("TERM").ok(),
+        colorterm: std::env::var("COLORTERM").ok(),
+    };
+    let mut line = serde_json::to_vec(&hello)?;
+    line.push(b'\n');
+    let handler: extern "C" fn(libc::c_int) = on_resize;
+    unsafe {
+        libc::signal(libc::SIGWINCH, handler as libc::sighandler_t);
+    }
+    let _saved = SavedTerminal::save()?;
+    send_fds(&stream, &line, &[0, 1])?;
+
+    // The server owns the terminal from now on; only tell it the new size when it changes,
+    // as the signal is delivered here.
+    stream.set_read_timeout(Some(Duration::from_millis(100)))?;
+    let mut reason = Vec::new();
+    let mut buf = [0; 64];
+    loop {
+        if RESIZED.swap(false, Ordering::SeqCst) {
+            stream.write_all(resize_line()?.as_bytes())?;
+        }
+        match stream.read(&mut buf) {
+            Ok(0) => break,
+            Ok(n) => reason.extend_from_slice(&buf[..n]),
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    ErrorKind::WouldBlock | ErrorKind::TimedOut | ErrorKind::Interrupted
+                ) => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(match reason.as_slice() {
+        b"detached\n" => Release::Detached,
+        _ => Release::Exited,
+    })
+}
+
+/// Sends `data` with the file descriptors `fds` attached.
+fn send_fds(stream: &UnixStream, data: &[u8], fds: &[RawFd]) -> io::Result<()> {
+    let fds_len = std::mem::size_of_val(fds) as u32;
+    // Aligned for the header, and large enough for a few descriptors.
+    let mut control = [0u64; 8];
+    unsafe {
+        let mut iov = libc::iovec {
+            iov_base: data.as_ptr() as *mut libc::c_void,
+            iov_len: data.len(),
+        };
+        let mut msg: libc::msghdr = std::mem::zeroed();
+        msg.msg_iov = &mut iov;
+        msg.msg_iovlen = 1;
+        msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+        msg.msg_controllen = libc::CMSG_SPACE(fds_len) as _;
+        let cmsg = libc::CMSG_FIRSTHDR(&msg);
+        (*cmsg).cmsg_level = libc::SOL_SOCKET;
+        (*cmsg).cmsg_type = libc::SCM_RIGHTS;
+        (*cmsg).cmsg_len = libc::CMSG_LEN(fds_len) as _;
+        std::ptr::copy_nonoverlapping(fds.as_ptr(), libc::CMSG_DATA(cmsg) as *mut RawFd, fds.len());
+        let n = libc::sendmsg(stream.as_raw_fd(), &msg, 0);
+        if n < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        if (n as usize) < data.len() {
+            (&*stream).write_all(&data[n as usize..])?;
+        }
+    }
+    Ok(())
+}
+
+/// Receives data into `buf` and the file descriptors sent with it.
+fn recv_fds(stream: &UnixStream, buf: &mut [u8]) -> io::Result<(usize, Vec<OwnedFd>)> {
+    let mut control = [0u64; 8];
+    let mut fds = Vec::new();
+    unsafe {
+        let mut iov = libc::iovec {
+            iov_base: buf.as_mut_ptr() as *mut libc::c_void,
+            iov_len: buf.len(),
+        };
+        let mut msg: libc::msghdr = std::mem::zeroed();
+        msg.msg_iov = &mut iov;
+        msg.msg_iovlen = 1;
+        msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+        msg.msg_controllen = std::mem::size_of_val(&control) as _;
+        let n = libc::recvmsg(stream.as_raw_fd(), &mut msg, 0);
+        if n < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let mut cmsg = libc::CMSG_FIRSTHDR(&msg);
+        while !cmsg.is_null() {
+            if (*cmsg).cmsg_level == libc::SOL_SOCKET && (*cmsg).cmsg_type == libc::SCM_RIGHTS {
+                let data = libc::CMSG_DATA(cmsg) as *const RawFd;
+                let len = (*cmsg).cmsg_len as usize - libc::CMSG_LEN(0) as usize;
+                for i in 0..len / std::mem::size_of::<RawFd>() {
+                    let fd = data.add(i).read_unaligned();
+                    // Keep the terminal of the client from leaking into the processes.
+                    libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC);
+                    fds.push(OwnedFd::from_raw_fd(fd));
+                }
+            }
+            cmsg = libc::CMSG_NXTHDR(&msg, cmsg);
+        }
+        Ok((n as usize, fds))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_resize_lines() {
+        assert_eq!(parse_resize(b"resize 120 40\n"), Some((120, 40)));
+        assert_eq!(parse_resize(b"resize 0 40\n"), None);
+        assert_eq!(parse_resize(b"resize 120\n"), None);
+        assert_eq!(parse_resize(b"resize\n"), None);
+        assert_eq!(parse_resize(b"resize a b\n"), None);
+    }
+}
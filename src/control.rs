@@ -0,0 +1,512 @@
+use std::io::{self, BufRead, BufReader, ErrorKind, Read, Write};
+use std::os::unix::fs::{DirBuilderExt, MetadataExt, PermissionsExt};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+/// Path of the control socket of the session running `procfile_hash`, in
+/// `$XDG_RUNTIME_DIR/sudare` or a per-user directory of the temporary directory.
+pub fn socket_path(procfile_hash: &str) -> PathBuf {
+    let dir = std::env::var("XDG_RUNTIME_DIR")
+        .map(|it| Path::new(&it).join("sudare"))
+        .unwrap_or_else(|_| {
+            std::env::temp_dir().join(format!("sudare-{}", unsafe { libc::getuid() }))
+        });
+    dir.join(format!("{}.sock", procfile_hash))
+}
+
+/// A JSON-RPC 2.0 request, one per line. Without an `id` it is a notification, which gets
+/// no response.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Request {
+    pub jsonrpc: String,
+    #[serde(
+        default,
+        deserialize_with = "present",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub id: Option<Value>,
+    pub method: String,
+    #[serde(default)]
+    pub params: Value,
+}
+
+/// Tells an `id` of `null` apart from a missing one, which `Option` alone would not.
+fn present<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<Option<Value>, D::Error> {
+    Value::deserialize(deserializer).map(Some)
+}
+
+/// A JSON-RPC 2.0 response, one per line.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Response {
+    pub jsonrpc: String,
+    pub id: Value,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub result: Option<Value>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<Error>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Error {
+    pub code: i64,
+    pub message: String,
+}
+
+impl Error {
+    const PARSE_ERROR: i64 = -32700;
+    const INVALID_REQUEST: i64 = -32600;
+    const METHOD_NOT_FOUND: i64 = -32601;
+    const INVALID_PARAMS: i64 = -32602;
+    const SERVER_ERROR: i64 = -32000;
+
+    pub fn method_not_found(method: &str) -> Self {
+        Self {
+            code: Self::METHOD_NOT_FOUND,
+            message: format!("unknown method `{}`", method),
+        }
+    }
+
+    pub fn invalid_params(message: impl Into<String>) -> Self {
+        Self {
+            code: Self::INVALID_PARAMS,
+            message: message.into(),
+        }
+    }
+
+    pub fn server(message: impl Into<String>) -> Self {
+        Self {
+            code: Self::SERVER_ERROR,
+            message: message.into(),
+        }
+    }
+}
+
+impl Response {
+    pub fn new(id: Value, result: Result<Value, Error>) -> Self {
+        let (result, error) = match result {
+            Ok(result) => (Some(result), None),
+            Err(error) => (None, Some(error)),
+        };
+        Self {
+            jsonrpc: "2.0".to_string(),
+            id,
+            result,
+            error,
+        }
+    }
+}
+
+/// Accessors of the parameters of a request, which are given by name.
+pub struct Params<'a>(pub &'a Value);
+
+impl<'a> Params<'a> {
+    pub fn str(&self, name: &str) -> Result<&'a str, Error> {
+        self.opt_str(name)?
+            .ok_or_else(|| Error::invalid_params(format!("missing `{}`", name)))
+    }
+
+    pub fn opt_str(&self, name: &str) -> Result<Option<&'a str>, Error> {
+        match self.0.get(name) {
+            None | Some(Value::Null) => Ok(None),
+            Some(Value::String(s)) => Ok(Some(s)),
+            Some(_) => Err(Error::invalid_params(format!(
+                "`{}` must be a string",
+                name
+            ))),
+        }
+    }
+
+    pub fn opt_value(&self, name: &str) -> Option<&'a Value> {
+        self.0.get(name).filter(|it| !it.is_null())
+    }
+}
+
+/// Fails unless `dir` is a directory of the user that nobody else can access, so that nobody
+/// else can replace the sockets in it.
+fn check_dir(dir: &Path) -> io::Result<()> {
+    let metadata = std::fs::symlink_metadata(dir)?;
+    if !metadata.is_dir()
+        || metadata.uid() != unsafe { libc::getuid() }
+        || metadata.mode() & 0o777 != 0o700
+    {
+        return Err(io::Error::new(
+            ErrorKind::PermissionDenied,
+            format!(
+                "{} must be a directory owned by you with mode 0700",
+                dir.display()
+            ),
+        ));
+    }
+    Ok(())
+}
+
+/// Fails when `path` does not fit in the address of a socket, e.g. under a long `$TMPDIR`.
+fn check_len(path: &Path) -> io::Result<()> {
+    let address: libc::sockaddr_un = unsafe { std::mem::zeroed() };
+    let max = address.sun_path.len() - 1;
+    if path.as_os_str().len() > max {
+        return Err(io::Error::new(
+            ErrorKind::InvalidInput,
+            format!(
+                "socket path {} is longer than {} bytes; set XDG_RUNTIME_DIR to a shorter directory",
+                path.display(),
+                max
+            ),
+        ));
+    }
+    Ok(())
+}
+
+/// Connects to the socket at `path`, once sure that only the user could have put it there.
+pub fn connect(path: &Path) -> io::Result<UnixStream> {
+    check_len(path)?;
+    if let Some(dir) = path.parent() {
+        check_dir(dir)?;
+    }
+    UnixStream::connect(path)
+}
+
+/// Binds a non-blocking socket at `path` that only the user can connect to, replacing a
+/// stale one. Fails with `AddrInUse` when another session is listening there.
+pub fn bind(path: &Path) -> io::Result<UnixListener> {
+    check_len(path)?;
+    if let Some(dir) = path.parent() {
+        std::fs::DirBuilder::new()
+            .recursive(true)
+            .mode(0o700)
+            .create(dir)?;
+        check_dir(dir)?;
+    }
+    if path.exists() {
+        if UnixStream::connect(path).is_ok() {
+            return Err(io::Error::new(
+                ErrorKind::AddrInUse,
+                format!("{} is in use by another session", path.display()),
+            ));
+        }
+        std::fs::remove_file(path)?;
+    }
+    let listener = UnixListener::bind(path)?;
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600))?;
+    listener.set_nonblocking(true)?;
+    Ok(listener)
+}
+
+/// Longest request accepted; clients sending longer lines are dropped.
+const MAX_REQUEST_SIZE: usize = 1024 * 1024;
+
+struct Client {
+    stream: UnixStream,
+    buffer: Vec<u8>,
+    /// Responses not written yet, as the client is slow to read them.
+    output: Vec<u8>,
+}
+
+impl Client {
+    /// Writes as much of the pending responses as the client accepts without blocking.
+    fn flush(&mut self) -> io::Result<()> {
+        while !self.output.is_empty() {
+            match self.stream.write(&self.output) {
+                Ok(0) => return Err(ErrorKind::WriteZero.into()),
+                Ok(n) => {
+                    self.output.drain(..n);
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Runs the request on `line` and returns its response, or `None` for a notification.
+fn respond(
+    line: &[u8],
+    handle: &mut impl FnMut(&Request) -> Result<Value, Error>,
+) -> Option<Response> {
+    let value = match serde_json::from_slice::<Value>(line) {
+        Ok(value) => value,
+        Err(e) => {
+            return Some(Response::new(
+                Value::Null,
+                Err(Error {
+                    code: Error::PARSE_ERROR,
+                    message: e.to_string(),
+                }),
+            ))
+        }
+    };
+    let id = value.get("id").cloned().unwrap_or(Value::Null);
+    let request = match serde_json::from_value::<Request>(value) {
+        Ok(request) if request.jsonrpc == "2.0" => request,
+        Ok(_) => {
+            return Some(Response::new(
+                id,
+                Err(Error {
+                    code: Error::INVALID_REQUEST,
+                    message: "`jsonrpc` must be \"2.0\"".to_string(),
+                }),
+            ))
+        }
+        Err(e) => {
+            return Some(Response::new(
+                id,
+                Err(Error {
+                    code: Error::INVALID_REQUEST,
+                    message: e.to_string(),
+                }),
+            ))
+        }
+    };
+    let result = handle(&request);
+    request.id.map(|id| Response::new(id, result))
+}
+
+/// Listens on the control socket, without blocking, from the main loop.
+pub struct Server {
+    path: PathBuf,
+    listener: UnixListener,
+    clients: Vec<Client>,
+}
+
+impl Server {
+    pub fn bind(path: &Path) -> io::Result<Self> {
+        Ok(Self {
+            path: path.to_path_buf(),
+            listener: bind(path)?,
+            clients: Vec::new(),
+        })
+    }
+
+    /// Accepts new clients and reads their requests. `handle` is called for every complete
+    /// request and its result is sent back, unless the request is a notification.
+    pub fn poll(&mut self, mut handle: impl FnMut(&Request) -> Result<Value, Error>) {
+        loop {
+            match self.listener.accept() {
+                Ok((stream, _)) => match stream.set_nonblocking(true) {
+                    Ok(()) => self.clients.push(Client {
+                        stream,
+                        buffer: Vec::new(),
+                        output: Vec::new(),
+                    }),
+                    Err(e) => log::error!("control error: {}", e),
+                },
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) => {
+                    log::error!("control error: {}", e);
+                    break;
+                }
+            }
+        }
+
+        self.clients.retain_mut(|client| {
+            // Read no more requests until the client has read the previous responses.
+            if !client.output.is_empty() {
+                return match client.flush() {
+                    Ok(()) => true,
+                    Err(e) => {
+                        log::error!("control error: {}", e);
+                        false
+                    }
+                };
+            }
+            let mut buf = [0; 4096];
+            let open = loop {
+                if client.buffer.len() > MAX_REQUEST_SIZE {
+                    break true;
+                }
+                match client.stream.read(&mut buf) {
+                    Ok(0) => break false,
+                    Ok(n) => client.buffer.extend_from_slice(&buf[..n]),
+                    Err(e) if e.kind() == ErrorKind::WouldBlock => break true,
+                    Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                    Err(_) => break false,
+                }
+            };
+            while let Some(end) = client.buffer.iter().position(|b| *b == b'\n') {
+                let line = client.buffer.drain(..=end).collect::<Vec<_>>();
+                if line.iter().all(|b| b.is_ascii_whitespace()) {
+                    continue;
+                }
+                let response = match respond(&line, &mut handle) {
+                    Some(response) => response,
+                    None => continue,
+                };
+                match serde_json::to_vec(&response) {
+                    Ok(line) => {
+                        client.output.extend_from_slice(&line);
+                        client.output.push(b'\n');
+                    }
+                    Err(e) => log::error!("control error: {}", e),
+                }
+            }
+            if client.buffer.len() > MAX_REQUEST_SIZE {
+                log::error!(
+                    "control error: request longer than {} bytes",
+                    MAX_REQUEST_SIZE
+                );
+                return false;
+            }
+            if let Err(e) = client.flush() {
+                log::error!("control error: {}", e);
+                return false;
+            }
+            open || !client.output.is_empty()
+        });
+    }
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+/// Sends a request to the session listening at `path` and waits for its response.
+pub fn call(path: &Path, method: &str, params: Value) -> io::Result<Response> {
+    let mut stream = connect(path)?;
+    let request = json!({ "jsonrpc": "2.0", "id": 1, "method": method, "params": params });
+    let mut line = serde_json::to_vec(&request)?;
+    line.push(b'\n');
+    stream.write_all(&line)?;
+    let mut response = String::new();
+    BufReader::new(stream).read_line(&mut response)?;
+    serde_json::from_str(&response).map_err(io::Error::from)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{Duration, Instant};
+
+    fn echo(request: &Request) -> Result<Value, Error> {
+        match request.method.as_str() {
+            "echo" => Ok(request.params.clone()),
+            method => Err(Error::method_not_found(method)),
+        }
+    }
+
+    fn response(line: &str) -> Option<Value> {
+        respond(line.as_bytes(), &mut echo).map(|it| serde_json::to_value(it).unwrap())
+    }
+
+    fn bound_server() -> (PathBuf, Server) {
+        let dir = std::env::temp_dir().join(format!(
+            "sudare-control-test-{}-{:?}",
+            std::process::id(),
+            std::thread::current().id()
+        ));
+        let _ = std::fs::remove_dir_all(&dir);
+        let path = dir.join("test.sock");
+        let server = Server::bind(&path).unwrap();
+        (dir, server)
+    }
+
+    /// Polls `server` until `done` or a few seconds have passed.
+    fn poll_until(server: &mut Server, mut done: impl FnMut(&Server) -> bool) {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !done(server) && Instant::now() < deadline {
+            server.poll(echo);
+            std::thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    fn answers_requests_with_their_id() {
+        assert_eq!(
+            response(r#"{"jsonrpc":"2.0","id":7,"method":"echo","params":[1]}"#),
+            Some(json!({ "jsonrpc": "2.0", "id": 7, "result": [1] }))
+        );
+        assert_eq!(
+            response(r#"{"jsonrpc":"2.0","id":null,"method":"echo"}"#),
+            Some(json!({ "jsonrpc": "2.0", "id": null, "result": null }))
+        );
+    }
+
+    #[test]
+    fn does_not_answer_notifications() {
+        let mut calls = 0;
+        let answer = respond(br#"{"jsonrpc":"2.0","method":"echo"}"#, &mut |it| {
+            calls += 1;
+            echo(it)
+        });
+        assert!(answer.is_none());
+        assert_eq!(calls, 1);
+        assert_eq!(response(r#"{"jsonrpc":"2.0","method":"nope"}"#), None);
+    }
+
+    #[test]
+    fn reports_errors() {
+        let code = |line: &str| response(line).unwrap()["error"]["code"].clone();
+        assert_eq!(code("{"), json!(Error::PARSE_ERROR));
+        assert_eq!(
+            code(r#"{"jsonrpc":"1.0","id":1,"method":"echo"}"#),
+            json!(Error::INVALID_REQUEST)
+        );
+        assert_eq!(
+            code(r#"{"id":1,"method":"echo"}"#),
+            json!(Error::INVALID_REQUEST)
+        );
+        assert_eq!(
+            code(r#"{"jsonrpc":"2.0","id":1}"#),
+            json!(Error::INVALID_REQUEST)
+        );
+        assert_eq!(code("[]"), json!(Error::INVALID_REQUEST));
+        assert_eq!(
+            code(r#"{"jsonrpc":"2.0","id":1,"method":"nope"}"#),
+            json!(Error::METHOD_NOT_FOUND)
+        );
+        assert_eq!(
+            response(r#"{"jsonrpc":"1.0","id":"a","method":"echo"}"#).unwrap()["id"],
+            json!("a")
+        );
+    }
+
+    #[test]
+    fn reads_requests_split_across_and_sharing_reads() {
+        let (dir, mut server) = bound_server();
+        let mut stream = UnixStream::connect(dir.join("test.sock")).unwrap();
+        stream
+            .write_all(b"{\"jsonrpc\":\"2.0\",\"id\":1,\"method\":\"echo\",")
+            .unwrap();
+        poll_until(&mut server, |it| !it.clients.is_empty());
+        server.poll(echo);
+        stream
+            .write_all(
+                b"\"params\":\"a\"}\n\n{\"jsonrpc\":\"2.0\",\"method\":\"echo\"}\n\
+                  {\"jsonrpc\":\"2.0\",\"id\":2,\"method\":\"echo\",\"params\":\"b\"}\n",
+            )
+            .unwrap();
+        server.poll(echo);
+        let mut reader = BufReader::new(stream);
+        let mut read = || {
+            let mut line = String::new();
+            reader.read_line(&mut line).unwrap();
+            serde_json::from_str::<Value>(&line).unwrap()
+        };
+        assert_eq!(read()["result"], json!("a"));
+        assert_eq!(read()["result"], json!("b"));
+        drop(server);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn drops_clients_sending_requests_over_the_size_cap() {
+        let (dir, mut server) = bound_server();
+        let mut stream = UnixStream::connect(dir.join("test.sock")).unwrap();
+        let writer = std::thread::spawn(move || {
+            // Fails once the server hangs up.
+            let _ = stream.write_all(&vec![b' '; MAX_REQUEST_SIZE + 4096]);
+        });
+        poll_until(&mut server, |it| !it.clients.is_empty());
+        poll_until(&mut server, |it| it.clients.is_empty());
+        assert!(server.clients.is_empty());
+        writer.join().unwrap();
+        drop(server);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
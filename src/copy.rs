@@ -0,0 +1,125 @@
+use std::io;
+use std::path::Path;
+
+use termwiz::color::{AnsiColor, ColorAttribute};
+use termwiz::escape::osc::Selection;
+use termwiz::escape::OperatingSystemCommand;
+use termwiz::surface::{Change, Line};
+use termwiz::terminal::Terminal;
+use wezterm_term::StableRowIndex;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SelectionKind {
+    /// Same columns on every row, like vi's `C-v`.
+    Rectangle,
+    /// Whole rows, like vi's `V`.
+    Line,
+}
+
+/// Cursor and selection over the screen and scrollback of a terminal. Positions are
+/// `(row, column)` so that they stay put while new output arrives.
+#[derive(Debug)]
+pub struct CopyMode {
+    pub cursor: (StableRowIndex, usize),
+    /// Kind of the selection and where it started.
+    pub selection: Option<(SelectionKind, (StableRowIndex, usize))>,
+}
+
+impl CopyMode {
+    pub fn new(cursor: (StableRowIndex, usize)) -> Self {
+        Self {
+            cursor,
+            selection: None,
+        }
+    }
+
+    /// Starts a selection at the cursor, changes its kind, or clears it when it already
+    /// has this kind.
+    pub fn toggle_selection(&mut self, kind: SelectionKind) {
+        self.selection = match self.selection {
+            Some((k, _)) if k == kind => None,
+            Some((_, anchor)) => Some((kind, anchor)),
+            None => Some((kind, self.cursor)),
+        };
+    }
+
+    /// Rows and columns covered by the selection, or the cursor when nothing is selected.
+    fn bounds(
+        &self,
+    ) -> (
+        SelectionKind,
+        (StableRowIndex, StableRowIndex),
+        (usize, usize),
+    ) {
+        let (kind, anchor) = self
+            .selection
+            .unwrap_or((SelectionKind::Rectangle, self.cursor));
+        (
+            kind,
+            (anchor.0.min(self.cursor.0), anchor.0.max(self.cursor.0)),
+            (anchor.1.min(self.cursor.1), anchor.1.max(self.cursor.1)),
+        )
+    }
+
+    fn contains(&self, row: StableRowIndex, col: usize) -> bool {
+        let (kind, rows, cols) = self.bounds();
+        rows.0 <= row
+            && row <= rows.1
+            && (kind == SelectionKind::Line || (cols.0 <= col && col <= cols.1))
+    }
+
+    /// Shows the selection and the cursor in a rendered row.
+    pub fn highlight(&self, line: &mut Line, row: StableRowIndex) {
+        let selecting = self.selection.is_some();
+        for (col, cell) in line.cells_mut().iter_mut().enumerate() {
+            let attrs = cell.attrs_mut();
+            if (row, col) == self.cursor {
+                attrs.set_background(ColorAttribute::from(AnsiColor::Fuchsia));
+                attrs.set_foreground(ColorAttribute::from(AnsiColor::White));
+            } else if selecting && self.contains(row, col) {
+                attrs.set_background(ColorAttribute::from(AnsiColor::Navy));
+                attrs.set_foreground(ColorAttribute::from(AnsiColor::White));
+            }
+        }
+    }
+
+    /// Text of the selection, or of the row under the cursor when nothing is selected.
+    /// `line` looks up a row of the terminal.
+    pub fn text(&self, line: impl Fn(StableRowIndex) -> Option<Line>) -> String {
+        let (kind, rows, cols) = match self.selection {
+            Some(_) => self.bounds(),
+            None => (SelectionKind::Line, (self.cursor.0, self.cursor.0), (0, 0)),
+        };
+        (rows.0..=rows.1)
+            .filter_map(line)
+            .map(|line| match kind {
+                SelectionKind::Rectangle => line.columns_as_str(cols.0..cols.1 + 1),
+                SelectionKind::Line => line.as_str().to_string(),
+            })
+            .map(|it| it.trim_end().to_string())
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+}
+
+/// Where a yanked selection goes.
+pub enum Yank {
+    /// The system clipboard, through OSC 52 which the outer terminal has to support.
+    Clipboard(String),
+    /// A file, for terminals that ignore OSC 52.
+    File(String),
+}
+
+/// Asks the terminal sudare runs in to put `text` into the system clipboard.
+pub fn set_clipboard(terminal: &mut impl Terminal, text: &str) -> termwiz::Result<()> {
+    let osc = OperatingSystemCommand::SetSelection(Selection::CLIPBOARD, text.to_string());
+    terminal.render(&[Change::Text(osc.to_string())])?;
+    terminal.flush()
+}
+
+pub fn write_file(path: &Path, text: &str) -> io::Result<()> {
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    std::fs::write(path, text)
+}
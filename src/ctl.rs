@@ -0,0 +1,420 @@
+use std::io::ErrorKind;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use serde_json::{json, Value};
+
+use crate::{control, procfile_hash, timestamp};
+
+const USAGE: &str = "\
+Usage: sudare ctl [-f|--procfile FILE] <command> [ARGS]
+
+Commands:
+  list                            Groups and their members
+  status [GROUP]                  State, pid, uptime and exit codes of the active members
+  start GROUP [MEMBER]            Start a member, the active one by default
+  stop GROUP [MEMBER]             Stop a member
+  restart GROUP [MEMBER]          Restart a member
+  select GROUP MEMBER             Make a member the active one
+  focus GROUP                     Focus a group
+  send-keys GROUP KEYS...         Type keys (ENTER, C-c, ...) or text into the active member
+  logs GROUP [MEMBER] [--tail N]  Last N lines of output (default: 100)
+
+Options:
+  -f, --procfile FILE  Procfile of the session (default: ./Procfile)
+  --json               Print the response as JSON
+  -n, --tail N         Lines printed by `logs`
+
+Everything after the group of `send-keys` is typed, and so is everything after `--`.";
+
+struct Options {
+    help: bool,
+    procfile_path: PathBuf,
+    json: bool,
+    tail: Option<u64>,
+    words: Vec<String>,
+}
+
+fn parse_args(args: &[String]) -> Result<Options, String> {
+    let mut options = Options {
+        help: false,
+        procfile_path: PathBuf::from("Procfile"),
+        json: false,
+        tail: None,
+        words: Vec::new(),
+    };
+    let mut it = args.iter();
+    while let Some(arg) = it.next() {
+        // Keys like `C-c` or `-n` are typed, not options.
+        if matches!(options.words.as_slice(), [command, _, ..] if command == "send-keys") {
+            options.words.push(arg.to_string());
+            continue;
+        }
+        match arg.as_str() {
+            "--" => options.words.extend(it.by_ref().cloned()),
+            "-h" | "--help" => options.help = true,
+            "-f" | "--procfile" => {
+                let value = it
+                    .next()
+                    .ok_or_else(|| format!("{} requires a value", arg))?;
+                options.procfile_path = PathBuf::from(value);
+            }
+            "--json" => options.json = true,
+            "--tail" | "-n" => {
+                let value = it
+                    .next()
+                    .ok_or_else(|| format!("{} requires a value", arg))?;
+                let tail = value
+                    .parse()
+                    .map_err(|_| format!("invalid line count `{}`", value))?;
+                options.tail = Some(tail);
+            }
+            _ => options.words.push(arg.to_string()),
+        }
+    }
+    Ok(options)
+}
+
+/// A member is given by its index or its label.
+fn member(s: &str) -> Value {
+    s.parse::<u64>()
+        .map(Value::from)
+        .unwrap_or_else(|_| json!(s))
+}
+
+/// The method and parameters of a command line.
+fn request(options: &Options) -> Result<(&str, Value), String> {
+    let words = options
+        .words
+        .iter()
+        .map(|it| it.as_str())
+        .collect::<Vec<_>>();
+    let request = match words.as_slice() {
+        ["list"] => ("list", Value::Null),
+        ["status"] => ("status", Value::Null),
+        ["status", group] => ("status", json!({ "group": group })),
+        [method @ ("start" | "stop" | "restart"), group] => (*method, json!({ "group": group })),
+        [method @ ("start" | "stop" | "restart"), group, m] => {
+            (*method, json!({ "group": group, "member": member(m) }))
+        }
+        ["select", group, m] => (
+            "select-member",
+            json!({ "group": group, "member": member(m) }),
+        ),
+        ["focus", group] => ("focus-group", json!({ "group": group })),
+        ["send-keys", group, keys @ ..] if !keys.is_empty() => {
+            ("send-keys", json!({ "group": group, "keys": keys }))
+        }
+        ["logs", group] => ("logs", json!({ "group": group, "tail": options.tail })),
+        ["logs", group, m] => (
+            "logs",
+            json!({ "group": group, "member": member(m), "tail": options.tail }),
+        ),
+        [] => return Err("You must specify a command".to_string()),
+        [command @ ("list" | "status" | "start" | "stop" | "restart" | "select" | "focus"
+        | "send-keys" | "logs"), ..] => return Err(format!("invalid arguments for `{}`", command)),
+        [command, ..] => return Err(format!("unknown command `{}`", command)),
+    };
+    Ok(request)
+}
+
+/// Runs a `sudare ctl` command against the session of a Procfile. Returns the exit code.
+pub fn run(args: &[String]) -> i32 {
+    let options = match parse_args(args) {
+        Ok(v) => v,
+        Err(e) => {
+            eprintln!("sudare: {}", e);
+            eprintln!("{}", USAGE);
+            return 2;
+        }
+    };
+    if options.help {
+        println!("{}", USAGE);
+        return 0;
+    }
+    let (method, params) = match request(&options) {
+        Ok(v) => v,
+        Err(e) => {
+            eprintln!("sudare: {}", e);
+            eprintln!("{}", USAGE);
+            return 2;
+        }
+    };
+
+    let procfile_path = match options.procfile_path.canonicalize() {
+        Ok(v) => v,
+        Err(e) => {
+            eprintln!("sudare: {}: {}", options.procfile_path.display(), e);
+            return 1;
+        }
+    };
+    let socket_path = control::socket_path(&procfile_hash(&procfile_path));
+    let response = match control::call(&socket_path, method, params) {
+        Ok(v) => v,
+        Err(e) if is_not_running(&e) => {
+            eprintln!(
+                "sudare: no session is running for {}\n(no control socket at {})",
+                procfile_path.display(),
+                socket_path.display()
+            );
+            return 1;
+        }
+        // Errors about the socket path itself already name it.
+        Err(e) if e.get_ref().is_some() => {
+            eprintln!("sudare: {}", e);
+            return 1;
+        }
+        Err(e) => {
+            eprintln!("sudare: {}: {}", socket_path.display(), e);
+            return 1;
+        }
+    };
+    let result = match (response.result, response.error) {
+        (_, Some(error)) => {
+            eprintln!("sudare: {}", error.message);
+            return 1;
+        }
+        (result, None) => result.unwrap_or(Value::Null),
+    };
+
+    if options.json {
+        match serde_json::to_string_pretty(&result) {
+            Ok(s) => println!("{}", s),
+            Err(e) => {
+                eprintln!("sudare: {}", e);
+                return 1;
+            }
+        }
+    } else {
+        print(method, &result);
+    }
+    0
+}
+
+/// A missing socket or one nobody listens on is left by no session or a crashed one.
+fn is_not_running(e: &std::io::Error) -> bool {
+    matches!(e.kind(), ErrorKind::NotFound | ErrorKind::ConnectionRefused)
+}
+
+fn print(method: &str, result: &Value) {
+    match method {
+        "list" | "focus-group" => {
+            for group in as_list(result) {
+                let members = group["members"]
+                    .as_array()
+                    .into_iter()
+                    .flatten()
+                    .map(|m| {
+                        let active = if m["index"] == group["active"] {
+                            "*"
+                        } else {
+                            ""
+                        };
+                        let running = if m["running"] == true { "+" } else { "" };
+                        format!("{}{}{}:{}", active, running, m["index"], str(&m["label"]))
+                    })
+                    .collect::<Vec<_>>();
+                let focused = if group["focused"] == true {
+                    " (focused)"
+                } else {
+                    ""
+                };
+                println!(
+                    "{}{} | {}",
+                    str(&group["group"]),
+                    focused,
+                    members.join(" ")
+                );
+            }
+        }
+        "status" | "start" | "stop" | "restart" | "select-member" => {
+            let rows = as_list(result)
+                .into_iter()
+                .map(|it| {
+                    let exit_code = match (&it["exit_code"], &it["last_exit_code"]) {
+                        (Value::Number(n), _) | (Value::Null, Value::Number(n)) => n.to_string(),
+                        _ => "-".to_string(),
+                    };
+                    [
+                        str(&it["group"]).to_string(),
+                        str(&it["member"]).to_string(),
+                        str(&it["state"]).to_string(),
+                        it["pid"]
+                            .as_u64()
+                            .map(|it| it.to_string())
+                            .unwrap_or("-".to_string()),
+                        it["uptime"]
+                            .as_u64()
+                            .map(|it| timestamp::format_duration(Duration::from_secs(it)))
+                            .unwrap_or("-".to_string()),
+                        exit_code,
+                        it["restarts"].to_string(),
+                    ]
+                })
+                .collect::<Vec<_>>();
+            print_table(
+                [
+                    "GROUP", "MEMBER", "STATE", "PID", "UPTIME", "EXIT", "RESTARTS",
+                ],
+                &rows,
+            );
+        }
+        "logs" => {
+            for line in as_list(result) {
+                println!("{}", str(line));
+            }
+        }
+        _ => {}
+    }
+}
+
+fn as_list(value: &Value) -> Vec<&Value> {
+    match value {
+        Value::Array(items) => items.iter().collect(),
+        Value::Null => Vec::new(),
+        value => vec![value],
+    }
+}
+
+fn str(value: &Value) -> &str {
+    value.as_str().unwrap_or("-")
+}
+
+fn print_table<const N: usize>(header: [&str; N], rows: &[[String; N]]) {
+    let mut widths = header.map(|it| it.len());
+    for row in rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.chars().count());
+        }
+    }
+    let format_row = |cells: Vec<&str>| {
+        cells
+            .iter()
+            .zip(widths)
+            .map(|(cell, width)| format!("{:<width$}", cell, width = width))
+            .collect::<Vec<_>>()
+            .join("  ")
+            .trim_end()
+            .to_string()
+    };
+    println!("{}", format_row(header.to_vec()));
+    for row in rows {
+        println!("{}", format_row(row.iter().map(|it| it.as_str()).collect()));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|it| it.to_string()).collect()
+    }
+
+    fn parse(a: &[&str]) -> Result<(String, Value), String> {
+        let options = parse_args(&args(a))?;
+        request(&options).map(|(method, params)| (method.to_string(), params))
+    }
+
+    #[test]
+    fn types_everything_after_the_group_of_send_keys() {
+        assert_eq!(
+            parse(&["send-keys", "web", "G", "C-c", "-n"]),
+            Ok((
+                "send-keys".to_string(),
+                json!({ "group": "web", "keys": ["G", "C-c", "-n"] })
+            ))
+        );
+        assert_eq!(
+            parse(&["send-keys", "web", "--json", "-f", "x"]).unwrap().1["keys"],
+            json!(["--json", "-f", "x"])
+        );
+    }
+
+    #[test]
+    fn takes_everything_after_double_dash_as_words() {
+        assert_eq!(
+            parse(&["send-keys", "--", "-web", "--tail"]),
+            Ok((
+                "send-keys".to_string(),
+                json!({ "group": "-web", "keys": ["--tail"] })
+            ))
+        );
+        assert_eq!(
+            parse(&["-f", "other/Procfile", "--json", "--", "status", "-n"]),
+            Ok(("status".to_string(), json!({ "group": "-n" })))
+        );
+    }
+
+    #[test]
+    fn parses_options_around_the_command() {
+        let options = parse_args(&args(&["logs", "-n", "5", "web", "--json", "-f", "P"])).unwrap();
+        assert!(options.json);
+        assert_eq!(options.procfile_path, PathBuf::from("P"));
+        assert_eq!(
+            request(&options),
+            Ok(("logs", json!({ "group": "web", "tail": 5 })))
+        );
+        assert_eq!(
+            parse(&["logs", "web", "1", "--tail", "20"]),
+            Ok((
+                "logs".to_string(),
+                json!({ "group": "web", "member": 1, "tail": 20 })
+            ))
+        );
+        assert_eq!(
+            parse(&["restart", "web", "api"]),
+            Ok((
+                "restart".to_string(),
+                json!({ "group": "web", "member": "api" })
+            ))
+        );
+        assert!(parse_args(&args(&["--help"])).unwrap().help);
+    }
+
+    #[test]
+    fn rejects_bad_line_counts() {
+        for tail in ["abc", "-1", "1.5", "18446744073709551616"] {
+            assert_eq!(
+                parse(&["logs", "web", "--tail", tail]).err(),
+                Some(format!("invalid line count `{}`", tail))
+            );
+        }
+        assert_eq!(
+            parse(&["logs", "web", "--tail"]).err(),
+            Some("--tail requires a value".to_string())
+        );
+        assert_eq!(
+            parse(&["-f"]).err(),
+            Some("-f requires a value".to_string())
+        );
+    }
+
+    #[test]
+    fn rejects_unknown_commands_and_invalid_arguments() {
+        assert_eq!(
+            parse(&["reboot", "web"]).err(),
+            Some("unknown command `reboot`".to_string())
+        );
+        assert_eq!(
+            parse(&[]).err(),
+            Some("You must specify a command".to_string())
+        );
+        for a in [
+            &["list", "web"][..],
+            &["start"],
+            &["stop", "web", "0", "1"],
+            &["select", "web"],
+            &["focus"],
+            &["send-keys", "web"],
+            &["logs"],
+        ] {
+            assert_eq!(
+                parse(a).err(),
+                Some(format!("invalid arguments for `{}`", a[0])),
+                "{:?}",
+                a
+            );
+        }
+    }
+}
@@ -0,0 +1,264 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
+
+pub type Env = BTreeMap<String, String>;
+
+#[derive(Debug)]
+pub enum Error {
+    Io(PathBuf, io::Error),
+    Parse {
+        path: PathBuf,
+        line: usize,
+        message: String,
+    },
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Io(path, e) => write!(f, "{}: {}", path.display(), e),
+            Error::Parse {
+                path,
+                line,
+                message,
+            } => write!(f, "{}:{}: {}", path.display(), line, message),
+        }
+    }
+}
+
+/// Reads a dotenv file into `env`. Variables already in `env` take part in interpolation and
+/// are overridden by the file.
+pub fn load(path: &Path, env: &mut Env) -> Result<(), Error> {
+    let content = std::fs::read_to_string(path).map_err(|e| Error::Io(path.to_path_buf(), e))?;
+    content.lines().enumerate().try_for_each(|(i, line)| {
+        parse_line(line, env).map_err(|message| Error::Parse {
+            path: path.to_path_buf(),
+            line: i + 1,
+            message,
+        })
+    })
+}
+
+fn parse_line(line: &str, env: &mut Env) -> Result<(), String> {
+    let line = line.trim();
+    if line.is_empty() || line.starts_with('#') {
+        return Ok(());
+    }
+    let line = line
+        .strip_prefix("export ")
+        .map(str::trim_start)
+        .unwrap_or(line);
+
+    let (key, value) = line
+        .split_once('=')
+        .ok_or_else(|| "expected KEY=VALUE".to_string())?;
+    let key = key.trim();
+    if !is_valid_key(key) {
+        return Err(format!("invalid variable name `{}`", key));
+    }
+
+    let value = value.trim_start();
+    let value = if let Some(rest) = value.strip_prefix('\'') {
+        let (quoted, rest) = rest
+            .split_once('\'')
+            .ok_or_else(|| "unterminated single quote".to_string())?;
+        expect_end(rest)?;
+        quoted.to_string()
+    } else if let Some(rest) = value.strip_prefix('"') {
+        let (quoted, rest) = split_double_quoted(rest)?;
+        expect_end(rest)?;
+        interpolate(quoted, env, true)?
+    } else {
+        let value = match value.find(" #") {
+            Some(i) => &value[..i],
+            None => value,
+        };
+        interpolate(value.trim_end(), env, false)?
+    };
+
+    env.insert(key.to_string(), value);
+    Ok(())
+}
+
+fn is_valid_key(key: &str) -> bool {
+    let mut chars = key.chars();
+    matches!(chars.next(), Some(c) if c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// Only whitespace or a comment may follow a quoted value.
+fn expect_end(rest: &str) -> Result<(), String> {
+    let rest = rest.trim();
+    if rest.is_empty() || rest.starts_with('#') {
+        Ok(())
+    } else {
+        Err(format!("unexpected `{}` after quoted value", rest))
+    }
+}
+
+/// Splits the content of a double quoted value from what follows the closing quote. Escape
+/// sequences are left for `interpolate`.
+fn split_double_quoted(s: &str) -> Result<(&str, &str), String> {
+    let mut chars = s.char_indices();
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '"' => return Ok((&s[..i], &s[i + 1..])),
+            '\\' => {
+                chars.next();
+            }
+            _ => {}
+        }
+    }
+    Err("unterminated double quote".to_string())
+}
+
+/// Expands `$VAR`, `${VAR}` and `${VAR:-default}` using `env`, then the environment of sudare.
+/// A backslash keeps the next `$` as is; in a double quoted value, which has `escapes`, it also
+/// starts the escape sequences `\n`, `\r` and `\t` and keeps any other character as is.
+fn interpolate(s: &str, env: &Env, escapes: bool) -> Result<String, String> {
+    let lookup = |name: &str| env.get(name).cloned().or_else(|| std::env::var(name).ok());
+
+    let mut value = String::new();
+    let mut chars = s.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' if escapes => match chars.next() {
+                Some('n') => value.push('\n'),
+                Some('r') => value.push('\r'),
+                Some('t') => value.push('\t'),
+                Some(c) => value.push(c),
+                None => value.push('\\'),
+            },
+            '\\' if chars.peek() == Some(&'$') => {
+                chars.next();
+                value.push('$');
+            }
+            '$' if chars.peek() == Some(&'{') => {
+                chars.next();
+                let mut expr = String::new();
+                loop {
+                    match chars.next() {
+                        Some('}') => break,
+                        Some(c) => expr.push(c),
+                        None => return Err(format!("unterminated `${{{}`", expr)),
+                    }
+                }
+                let (name, default) = match expr.split_once(":-") {
+                    Some((name, default)) => (name, Some(default)),
+                    None => (expr.as_str(), None),
+                };
+                if !is_valid_key(name) {
+                    return Err(format!("invalid variable name `{}`", name));
+                }
+                match lookup(name).filter(|v| !v.is_empty() || default.is_none()) {
+                    Some(v) => value.push_str(&v),
+                    None => value.push_str(default.unwrap_or("")),
+                }
+            }
+            '$' if matches!(chars.peek(), Some(c) if c.is_ascii_alphabetic() || *c == '_') => {
+                let mut name = String::new();
+                while let Some(c) = chars.peek() {
+                    if c.is_ascii_alphanumeric() || *c == '_' {
+                        name.push(*c);
+                        chars.next();
+                    } else {
+                        break;
+                    }
+                }
+                value.push_str(&lookup(&name).unwrap_or_default());
+            }
+            _ => value.push(c),
+        }
+    }
+    Ok(value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(lines: &[&str]) -> Result<Env, String> {
+        let mut env = Env::new();
+        for line in lines {
+            parse_line(line, &mut env)?;
+        }
+        Ok(env)
+    }
+
+    fn value(line: &str) -> String {
+        let env = parse(&["HOME=/home/me", "EMPTY=", line]).unwrap();
+        env["V"].clone()
+    }
+
+    #[test]
+    fn parses_plain_values_and_export() {
+        let env = parse(&["# comment", "", "A=1", "export B = two words ", "C="]).unwrap();
+        assert_eq!(env["A"], "1");
+        assert_eq!(env["B"], "two words");
+        assert_eq!(env["C"], "");
+    }
+
+    #[test]
+    fn keeps_single_quoted_values_as_is() {
+        assert_eq!(value(r"V='$HOME \n # x'"), r"$HOME \n # x");
+    }
+
+    #[test]
+    fn resolves_escapes_and_variables_in_double_quotes() {
+        assert_eq!(value(r#"V="a\tb\"c\\d""#), "a\tb\"c\\d");
+        assert_eq!(value(r#"V="$HOME/${HOME}""#), "/home/me//home/me");
+        assert_eq!(value(r#"V="\$HOME""#), "$HOME");
+        assert_eq!(value(r#"V="a\\$HOME""#), r"a\/home/me");
+    }
+
+    #[test]
+    fn resolves_variables_in_plain_values() {
+        assert_eq!(value(r"V=$HOME/x"), "/home/me/x");
+        assert_eq!(value(r"V=\$HOME"), "$HOME");
+        assert_eq!(value(r"V=C:\dir"), r"C:\dir");
+    }
+
+    #[test]
+    fn strips_inline_comments() {
+        assert_eq!(value("V=a # comment"), "a");
+        assert_eq!(value("V=a#b"), "a#b");
+        assert_eq!(value(r#"V="a # b" # comment"#), "a # b");
+        assert_eq!(value("V='a' # comment"), "a");
+    }
+
+    #[test]
+    fn falls_back_to_defaults() {
+        assert_eq!(value("V=${SUDARE_TEST_UNSET:-fallback}"), "fallback");
+        assert_eq!(value("V=${EMPTY:-fallback}"), "fallback");
+        assert_eq!(value("V=${HOME:-fallback}"), "/home/me");
+        assert_eq!(value("V=${SUDARE_TEST_UNSET}"), "");
+    }
+
+    #[test]
+    fn rejects_invalid_lines() {
+        let error = |line: &str| parse(&[line]).unwrap_err();
+        assert_eq!(error("A"), "expected KEY=VALUE");
+        assert_eq!(error("A.B=1"), "invalid variable name `A.B`");
+        assert_eq!(error("1A=1"), "invalid variable name `1A`");
+        assert_eq!(error("A='x"), "unterminated single quote");
+        assert_eq!(error(r#"A="x\""#), "unterminated double quote");
+        assert_eq!(error("A='x' y"), "unexpected `y` after quoted value");
+        assert_eq!(error("A=${B"), "unterminated `${B`");
+    }
+
+    #[test]
+    fn reports_the_line_of_an_error() {
+        let path = std::env::temp_dir().join(format!("sudare-dotenv-test-{}", std::process::id()));
+        std::fs::write(&path, "A=1\n\n# comment\nB\n").unwrap();
+        let result = load(&path, &mut Env::new());
+        std::fs::remove_file(&path).unwrap();
+        match result {
+            Err(Error::Parse { line, message, .. }) => {
+                assert_eq!((line, message.as_str()), (4, "expected KEY=VALUE"))
+            }
+            _ => panic!("the file should not parse"),
+        }
+    }
+}
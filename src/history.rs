@@ -0,0 +1,77 @@
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::ops::Range;
+use std::os::unix::fs::FileExt;
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Rows that scrolled off the screen of a process, saved as text to a file so that they
+/// remain reachable after the in-memory scrollback drops them. Row `n` of the history is
+/// stable row `n` of the terminal. Each row is saved with the time it was printed, in
+/// milliseconds since the epoch, and a tab.
+pub struct History {
+    file: File,
+    /// Offset of the start of each row in the file; only these are kept in memory.
+    offsets: Vec<u64>,
+    len: u64,
+}
+
+impl History {
+    /// Creates the file, truncating the history of a previous session.
+    pub fn create(path: &Path) -> io::Result<Self> {
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path)?;
+        Ok(Self {
+            file,
+            offsets: Vec::new(),
+            len: 0,
+        })
+    }
+
+    /// Number of rows saved.
+    pub fn len(&self) -> usize {
+        self.offsets.len()
+    }
+
+    pub fn push(&mut self, row: &str, time: Option<SystemTime>) -> io::Result<()> {
+        let time = time
+            .and_then(|it| it.duration_since(UNIX_EPOCH).ok())
+            .map(|it| it.as_millis().to_string())
+            .unwrap_or_default();
+        let bytes = format!("{}\t{}\n", time, row.replace('\n', " ")).into_bytes();
+        self.file.write_all_at(&bytes, self.len)?;
+        self.offsets.push(self.len);
+        self.len += bytes.len() as u64;
+        Ok(())
+    }
+
+    /// Reads the rows in `rows` that have been saved, with the time they were printed.
+    pub fn rows(&self, rows: Range<usize>) -> io::Result<Vec<(Option<SystemTime>, String)>> {
+        let rows = rows.start..rows.end.min(self.len());
+        if rows.is_empty() {
+            return Ok(Vec::new());
+        }
+        let start = self.offsets[rows.start];
+        let end = self.offsets.get(rows.end).copied().unwrap_or(self.len);
+        let mut buf = vec![0; (end - start) as usize];
+        self.file.read_exact_at(&mut buf, start)?;
+        Ok(String::from_utf8_lossy(&buf)
+            .lines()
+            .map(|line| {
+                let (time, text) = line.split_once('\t').unwrap_or(("", line));
+                let time = time
+                    .parse::<u64>()
+                    .ok()
+                    .map(|ms| UNIX_EPOCH + Duration::from_millis(ms));
+                (time, text.to_string())
+            })
+            .collect())
+    }
+}
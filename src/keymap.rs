@@ -0,0 +1,510 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use termwiz::input::{KeyCode, KeyEvent, Modifiers};
+
+/// Something the user can do with a key.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    Quit,
+    /// Leave the processes running in the background.
+    Detach,
+    NextGroup,
+    PrevGroup,
+    /// Select the member at this index in the focused group.
+    SelectMember(usize),
+    /// Start or stop the active member of the focused group.
+    ToggleMember,
+    /// Restart the active member of the focused group.
+    Restart,
+    ScrollUp,
+    ScrollDown,
+    Search,
+    CopyMode,
+    ToggleTimestamps,
+    Zoom,
+    CycleLayout,
+    /// Toggle sending keys to the active process. Its bindings work in both modes.
+    InsertMode,
+    Help,
+}
+
+impl Action {
+    /// Name in the keymap file and description of each action but `SelectMember`.
+    const NAMES: [(Action, &'static str, &'static str); 15] = [
+        (Action::Quit, "quit", "Exit"),
+        (
+            Action::Detach,
+            "detach",
+            "Detach, keeping processes running",
+        ),
+        (Action::NextGroup, "next-group", "Next process group"),
+        (Action::PrevGroup, "prev-group", "Previous process group"),
+        (
+            Action::ToggleMember,
+            "toggle-member",
+            "Start/stop active process",
+        ),
+        (Action::Restart, "restart", "Restart active process"),
+        (Action::ScrollUp, "scroll-up", "Scroll up"),
+        (Action::ScrollDown, "scroll-down", "Scroll down"),
+        (Action::Search, "search", "Search output of active process"),
+        (Action::CopyMode, "copy-mode", "Enter copy mode"),
+        (
+            Action::ToggleTimestamps,
+            "toggle-timestamps",
+            "Toggle timestamps",
+        ),
+        (Action::Zoom, "zoom", "Zoom focused group"),
+        (Action::CycleLayout, "cycle-layout", "Cycle layout"),
+        (Action::InsertMode, "insert-mode", "Toggle insert mode"),
+        (Action::Help, "help", "Show key bindings"),
+    ];
+
+    pub fn description(&self) -> String {
+        match self {
+            Action::SelectMember(index) => format!("Select process {} in group", index),
+            _ => Self::NAMES
+                .iter()
+                .find(|(action, _, _)| action == self)
+                .map(|(_, _, description)| description.to_string())
+                .unwrap_or_default(),
+        }
+    }
+}
+
+impl fmt::Display for Action {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Action::SelectMember(index) => write!(f, "select-member-{}", index),
+            _ => {
+                let name = Self::NAMES
+                    .iter()
+                    .find(|(action, _, _)| action == self)
+                    .map(|(_, name, _)| *name)
+                    .unwrap_or_default();
+                f.write_str(name)
+            }
+        }
+    }
+}
+
+impl FromStr for Action {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(index) = s.strip_prefix("select-member-") {
+            return index
+                .parse()
+                .map(Action::SelectMember)
+                .map_err(|_| format!("invalid member index `{}`", index));
+        }
+        Self::NAMES
+            .iter()
+            .find(|(_, name, _)| *name == s)
+            .map(|(action, _, _)| *action)
+            .ok_or_else(|| format!("unknown action `{}`", s))
+    }
+}
+
+/// Names of keys other than characters, as written in the keymap file.
+const KEY_NAMES: [(KeyCode, &str); 15] = [
+    (KeyCode::Escape, "ESC"),
+    (KeyCode::Enter, "ENTER"),
+    (KeyCode::Tab, "TAB"),
+    (KeyCode::Char(' '), "SPACE"),
+    (KeyCode::Backspace, "BS"),
+    (KeyCode::Delete, "DEL"),
+    (KeyCode::Insert, "INS"),
+    (KeyCode::UpArrow, "UP"),
+    (KeyCode::DownArrow, "DOWN"),
+    (KeyCode::LeftArrow, "LEFT"),
+    (KeyCode::RightArrow, "RIGHT"),
+    (KeyCode::PageUp, "PGUP"),
+    (KeyCode::PageDown, "PGDN"),
+    (KeyCode::Home, "HOME"),
+    (KeyCode::End, "END"),
+];
+
+/// Modifiers told apart by bindings, the others (left or right, ...) are ignored.
+fn modifiers(key: &KeyEvent) -> Modifiers {
+    let mut modifiers = key.modifiers & (Modifiers::SHIFT | Modifiers::ALT | Modifiers::CTRL);
+    if let KeyCode::Char(_) = key.key {
+        // The case of the character already tells whether shift was held.
+        modifiers.remove(Modifiers::SHIFT);
+    }
+    modifiers
+}
+
+/// Whether `key` was typed as `binding`, which was parsed from the keymap file.
+fn matches(binding: &KeyEvent, key: &KeyEvent) -> bool {
+    let same_key = match (binding.key, key.key) {
+        // Terminals may report C-B for C-b.
+        (KeyCode::Char(a), KeyCode::Char(b)) if binding.modifiers.contains(Modifiers::CTRL) => {
+            a.eq_ignore_ascii_case(&b)
+        }
+        (a, b) => a == b,
+    };
+    same_key && binding.modifiers == modifiers(key)
+}
+
+/// Parses a key like `j`, `N`, `C-b`, `M-x`, `S-TAB` or `F5`.
+pub fn parse_key(s: &str) -> Result<KeyEvent, String> {
+    let mut modifiers = Modifiers::NONE;
+    let mut rest = s;
+    while rest.len() > 2 && rest.as_bytes()[1] == b'-' {
+        modifiers |= match &rest[..1] {
+            "C" => Modifiers::CTRL,
+            "M" | "A" => Modifiers::ALT,
+            "S" => Modifiers::SHIFT,
+            _ => return Err(format!("unknown modifier in `{}`", s)),
+        };
+        rest = &rest[2..];
+    }
+    let mut chars = rest.chars();
+    let key = match (chars.next(), chars.next()) {
+        (Some(c), None) => KeyCode::Char(c),
+        _ => KEY_NAMES
+            .iter()
+            .find(|(_, name)| name.eq_ignore_ascii_case(rest))
+            .map(|(code, _)| *code)
+            .or_else(|| {
+                let n = rest.strip_prefix(['F', 'f'])?.parse::<u8>().ok()?;
+                (1..=12).contains(&n).then_some(KeyCode::Function(n))
+            })
+            .ok_or_else(|| format!("unknown key `{}`", s))?,
+    };
+    if let KeyCode::Char(_) = key {
+        modifiers.remove(Modifiers::SHIFT);
+    }
+    Ok(KeyEvent { key, modifiers })
+}
+
+/// Formats a key the way it is written in the keymap file.
+fn format_key(key: &KeyEvent) -> String {
+    let mut s = String::new();
+    for (modifier, prefix) in [
+        (Modifiers::CTRL, "C-"),
+        (Modifiers::ALT, "M-"),
+        (Modifiers::SHIFT, "S-"),
+    ] {
+        if key.modifiers.contains(modifier) {
+            s.push_str(prefix);
+        }
+    }
+    match (KEY_NAMES.iter().find(|(code, _)| *code == key.key), key.key) {
+        (Some((_, name)), _) => s.push_str(name),
+        (None, KeyCode::Char(c)) => s.push(c),
+        (None, KeyCode::Function(n)) => s.push_str(&format!("F{}", n)),
+        (None, code) => s.push_str(&format!("{:?}", code)),
+    }
+    s
+}
+
+/// Parses a sequence of keys separated by spaces, like `C-a n`.
+fn parse_keys(s: &str) -> Result<Vec<KeyEvent>, String> {
+    let keys = s
+        .split_whitespace()
+        .map(parse_key)
+        .collect::<Result<Vec<_>, _>>()?;
+    if keys.is_empty() {
+        return Err("empty key".to_string());
+    }
+    Ok(keys)
+}
+
+const DEFAULT_BINDINGS: [(&str, &str); 27] = [
+    ("ESC", "quit"),
+    ("d", "detach"),
+    ("n", "next-group"),
+    ("DOWN", "next-group"),
+    ("p", "prev-group"),
+    ("UP", "prev-group"),
+    ("0", "select-member-0"),
+    ("1", "select-member-1"),
+    ("2", "select-member-2"),
+    ("3", "select-member-3"),
+    ("4", "select-member-4"),
+    ("5", "select-member-5"),
+    ("6", "select-member-6"),
+    ("7", "select-member-7"),
+    ("8", "select-member-8"),
+    ("9", "select-member-9"),
+    ("s", "toggle-member"),
+    ("r", "restart"),
+    ("k", "scroll-up"),
+    ("j", "scroll-down"),
+    ("C-b", "insert-mode"),
+    ("/", "search"),
+    ("c", "copy-mode"),
+    ("t", "toggle-timestamps"),
+    ("z", "zoom"),
+    ("l", "cycle-layout"),
+    ("?", "help"),
+];
+
+/// What a key typed did.
+#[derive(Debug)]
+pub enum Lookup {
+    Action(Action),
+    /// The keys typed so far start a longer binding.
+    Pending,
+    /// The keys typed so far are not bound, and are given back.
+    Unbound(Vec<KeyEvent>),
+}
+
+/// Key bindings, and the start of a sequence of keys typed so far.
+pub struct Keymap {
+    bindings: Vec<(Vec<KeyEvent>, Action)>,
+    pending: Vec<KeyEvent>,
+}
+
+impl Keymap {
+    /// `$XDG_CONFIG_HOME/sudare/keymap.json`
+    pub fn default_path() -> PathBuf {
+        let config_home: String = std::env::var("XDG_CONFIG_HOME")
+            .or_else(|_e| std::env::var("HOME").map(|v| v + "/.config"))
+            .unwrap();
+        Path::new(&config_home).join("sudare").join("keymap.json")
+    }
+
+    /// The default bindings, overridden by those in the file at `path` if it exists. The file
+    /// is a JSON object from keys to actions; `none` removes a default binding.
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let overrides = if path.exists() {
+            let text =
+                std::fs::read_to_string(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+            serde_json::from_str::<BTreeMap<String, String>>(&text)
+                .map_err(|e| format!("{}: {}", path.display(), e))?
+        } else {
+            BTreeMap::new()
+        };
+
+        let mut bindings: Vec<(Vec<KeyEvent>, Option<Action>)> = Vec::new();
+        let defaults = DEFAULT_BINDINGS.iter().map(|(k, a)| (*k, *a));
+        let overrides = overrides.iter().map(|(k, a)| (k.as_str(), a.as_str()));
+        for (keys, action) in defaults.chain(overrides) {
+            let error = |e: String| format!("{}: `{}`: {}", path.display(), keys, e);
+            let keys = parse_keys(keys).map_err(error)?;
+            let action = match action {
+                "none" => None,
+                _ => Some(action.parse::<Action>().map_err(error)?),
+            };
+            match bindings.iter_mut().find(|(k, _)| *k == keys) {
+                Some(binding) => binding.1 = action,
+                None => bindings.push((keys, action)),
+            }
+        }
+        Ok(Self {
+            bindings: bindings
+                .into_iter()
+                .filter_map(|(keys, action)| action.map(|a| (keys, a)))
+                .collect(),
+            pending: Vec::new(),
+        })
+    }
+
+    /// The keys bound to each action, formatted for display, in the order of the defaults.
+    pub fn help(&self) -> Vec<(String, Action)> {
+        let mut help: Vec<(String, Action)> = Vec::new();
+        for (keys, action) in self.bindings.iter() {
+            let keys = keys.iter().map(format_key).collect::<Vec<_>>().join(" ");
+            match help.iter_mut().find(|(_, a)| a == action) {
+                Some((k, _)) => {
+                    k.push_str(", ");
+                    k.push_str(&keys);
+                }
+                None => help.push((keys, *action)),
+            }
+        }
+        help
+    }
+
+    /// Adds a typed key to the sequence, only considering the bindings of actions for which
+    /// `enabled` returns true. A sequence that is bound runs its action at once, even if it
+    /// also starts a longer binding.
+    pub fn feed(&mut self, key: &KeyEvent, enabled: impl Fn(Action) -> bool) -> Lookup {
+        self.pending.push(key.clone());
+        let pending = &self.pending;
+        let starts_with = |keys: &[KeyEvent]| {
+            keys.len() >= pending.len()
+                && keys.iter().zip(pending.iter()).all(|(b, k)| matches(b, k))
+        };
+        let candidates = self
+            .bindings
+            .iter()
+            .filter(|(keys, action)| enabled(*action) && starts_with(keys))
+            .collect::<Vec<_>>();
+        if let Some((_, action)) = candidates
+            .iter()
+            .find(|(keys, _)| keys.len() == pending.len())
+        {
+            let action = *action;
+            self.pending.clear();
+            Lookup::Action(action)
+        } else if candidates.is_empty() {
+            Lookup::Unbound(std::mem::take(&mut self.pending))
+        } else {
+            Lookup::Pending
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(key: KeyCode, modifiers: Modifiers) -> KeyEvent {
+        KeyEvent { key, modifiers }
+    }
+
+    fn char(c: char) -> KeyEvent {
+        key(KeyCode::Char(c), Modifiers::NONE)
+    }
+
+    /// Loads `json` as the keymap file.
+    fn load(json: &str) -> Result<Keymap, String> {
+        let path = std::env::temp_dir().join(format!(
+            "sudare-keymap-test-{}-{:?}.json",
+            std::process::id(),
+            std::thread::current().id()
+        ));
+        std::fs::write(&path, json).unwrap();
+        let keymap = Keymap::load(&path);
+        std::fs::remove_file(&path).unwrap();
+        keymap.map_err(|e| e.replace(&path.display().to_string(), "keymap.json"))
+    }
+
+    fn action(keymap: &mut Keymap, keys: &str) -> Option<Action> {
+        let mut action = None;
+        for k in parse_keys(keys).unwrap() {
+            action = match keymap.feed(&k, |_| true) {
+                Lookup::Action(a) => Some(a),
+                _ => None,
+            };
+        }
+        action
+    }
+
+    #[test]
+    fn parses_keys() {
+        assert_eq!(parse_key("j"), Ok(char('j')));
+        assert_eq!(parse_key("N"), Ok(char('N')));
+        assert_eq!(parse_key("-"), Ok(char('-')));
+        assert_eq!(
+            parse_key("C-b"),
+            Ok(key(KeyCode::Char('b'), Modifiers::CTRL))
+        );
+        assert_eq!(
+            parse_key("C--"),
+            Ok(key(KeyCode::Char('-'), Modifiers::CTRL))
+        );
+        assert_eq!(
+            parse_key("C-M-x"),
+            Ok(key(KeyCode::Char('x'), Modifiers::CTRL | Modifiers::ALT))
+        );
+        assert_eq!(parse_key("A-x"), parse_key("M-x"));
+        // Shift is told by the case of a character.
+        assert_eq!(parse_key("S-a"), Ok(char('a')));
+        assert_eq!(parse_key("S-TAB"), Ok(key(KeyCode::Tab, Modifiers::SHIFT)));
+        assert_eq!(parse_key("esc"), Ok(key(KeyCode::Escape, Modifiers::NONE)));
+        assert_eq!(parse_key("SPACE"), Ok(char(' ')));
+        assert_eq!(
+            parse_key("F12"),
+            Ok(key(KeyCode::Function(12), Modifiers::NONE))
+        );
+        assert_eq!(parse_key("F13"), Err("unknown key `F13`".to_string()));
+        assert_eq!(
+            parse_key("X-a"),
+            Err("unknown modifier in `X-a`".to_string())
+        );
+        assert_eq!(parse_key("FOO"), Err("unknown key `FOO`".to_string()));
+        assert_eq!(parse_keys(" "), Err("empty key".to_string()));
+    }
+
+    #[test]
+    fn formats_keys_as_parsed() {
+        for s in ["j", "N", "C-b", "C-M-x", "S-TAB", "ESC", "SPACE", "F5"] {
+            assert_eq!(format_key(&parse_key(s).unwrap()), s);
+        }
+    }
+
+    #[test]
+    fn runs_chords_and_gives_back_unbound_keys() {
+        let mut keymap = load(r#"{ "C-a r": "restart", "C-a C-a": "insert-mode" }"#).unwrap();
+        let prefix = key(KeyCode::Char('a'), Modifiers::CTRL);
+        assert!(matches!(keymap.feed(&prefix, |_| true), Lookup::Pending));
+        assert!(matches!(
+            keymap.feed(&char('r'), |_| true),
+            Lookup::Action(Action::Restart)
+        ));
+
+        assert!(matches!(keymap.feed(&prefix, |_| true), Lookup::Pending));
+        match keymap.feed(&char('x'), |_| true) {
+            Lookup::Unbound(keys) => assert_eq!(keys, vec![prefix.clone(), char('x')]),
+            other => panic!("unexpected {:?}", other),
+        }
+
+        // Only the enabled actions are considered, like in insert mode.
+        let insert_mode = |action| action == Action::InsertMode;
+        assert!(matches!(keymap.feed(&prefix, insert_mode), Lookup::Pending));
+        assert!(matches!(
+            keymap.feed(&char('r'), insert_mode),
+            Lookup::Unbound(_)
+        ));
+        assert!(matches!(
+            keymap.feed(&char('j'), insert_mode),
+            Lookup::Unbound(_)
+        ));
+    }
+
+    #[test]
+    fn matches_control_keys_of_either_case() {
+        let mut keymap = load("{}").unwrap();
+        let typed = key(KeyCode::Char('B'), Modifiers::CTRL | Modifiers::SHIFT);
+        assert!(matches!(
+            keymap.feed(&typed, |_| true),
+            Lookup::Action(Action::InsertMode)
+        ));
+    }
+
+    #[test]
+    fn overrides_the_default_bindings() {
+        let mut keymap =
+            load(r#"{ "j": "scroll-up", "k": "scroll-down", "ESC": "none", "C-a q": "quit" }"#)
+                .unwrap();
+        assert_eq!(action(&mut keymap, "j"), Some(Action::ScrollUp));
+        assert_eq!(action(&mut keymap, "k"), Some(Action::ScrollDown));
+        assert_eq!(action(&mut keymap, "ESC"), None);
+        assert_eq!(action(&mut keymap, "C-a q"), Some(Action::Quit));
+        assert_eq!(action(&mut keymap, "3"), Some(Action::SelectMember(3)));
+        let help = keymap.help();
+        assert!(help.contains(&("C-a q".to_string(), Action::Quit)));
+        assert!(help.contains(&("n, DOWN".to_string(), Action::NextGroup)));
+    }
+
+    #[test]
+    fn loads_the_defaults_without_a_file() {
+        let mut keymap = Keymap::load(Path::new("/nonexistent/keymap.json")).unwrap();
+        assert_eq!(action(&mut keymap, "n"), Some(Action::NextGroup));
+        assert_eq!(action(&mut keymap, "ESC"), Some(Action::Quit));
+    }
+
+    #[test]
+    fn reports_invalid_bindings() {
+        assert_eq!(
+            load(r#"{ "j": "jump" }"#).err(),
+            Some("keymap.json: `j`: unknown action `jump`".to_string())
+        );
+        assert_eq!(
+            load(r#"{ "C-a FOO": "quit" }"#).err(),
+            Some("keymap.json: `C-a FOO`: unknown key `FOO`".to_string())
+        );
+        assert_eq!(
+            load(r#"{ "x": "select-member-x" }"#).err(),
+            Some("keymap.json: `x`: invalid member index `x`".to_string())
+        );
+    }
+}
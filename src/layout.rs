@@ -0,0 +1,156 @@
+use std::fmt;
+use std::str::FromStr;
+
+/// How process groups are arranged on the screen.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Layout {
+    /// Groups stacked vertically, the focused one taking the remaining rows.
+    #[default]
+    Stack,
+    /// Groups side by side.
+    Horizontal,
+    /// Groups in rows and columns of the same size.
+    Grid,
+    /// Only the focused group, below a bar with the title of every group.
+    Tabs,
+}
+
+impl Layout {
+    pub const ALL: [Layout; 4] = [
+        Layout::Stack,
+        Layout::Horizontal,
+        Layout::Grid,
+        Layout::Tabs,
+    ];
+
+    pub fn next(self) -> Self {
+        let i = Self::ALL.iter().position(|it| *it == self).unwrap_or(0);
+        Self::ALL[(i + 1) % Self::ALL.len()]
+    }
+}
+
+impl fmt::Display for Layout {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Layout::Stack => "stack",
+            Layout::Horizontal => "horizontal",
+            Layout::Grid => "grid",
+            Layout::Tabs => "tabs",
+        })
+    }
+}
+
+impl FromStr for Layout {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::ALL
+            .iter()
+            .find(|it| it.to_string() == s)
+            .copied()
+            .ok_or_else(|| format!("unknown layout `{}`", s))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Rect {
+    pub x: usize,
+    pub y: usize,
+    pub width: usize,
+    pub height: usize,
+}
+
+/// Splits `length` into `n` parts separated by `gap` cells, the first parts taking the
+/// remainder. Returns the start and the size of each part.
+fn split(length: usize, n: usize, gap: usize) -> Vec<(usize, usize)> {
+    let available = length.saturating_sub(n.saturating_sub(1) * gap);
+    let (base, remainder) = (available / n, available % n);
+    (0..n)
+        .scan(0, |start, i| {
+            let size = base + usize::from(i < remainder);
+            let part = (*start, size);
+            *start += size + gap;
+            Some(part)
+        })
+        .collect()
+}
+
+/// Where each of `n` windows goes in a `width` x `height` area, or `None` for the windows
+/// that are hidden. Unfocused windows of the stack layout get `min_height` rows of output.
+pub fn arrange(
+    layout: Layout,
+    n: usize,
+    focused: usize,
+    width: usize,
+    height: usize,
+    min_height: usize,
+) -> Vec<Option<Rect>> {
+    if n == 0 {
+        return Vec::new();
+    }
+    match layout {
+        Layout::Stack => {
+            let unfocused_height = 1 + min_height;
+            let focused_height = height
+                .saturating_sub((n - 1) * unfocused_height)
+                .max(unfocused_height);
+            (0..n)
+                .scan(0, |y, i| {
+                    let h = if i == focused {
+                        focused_height
+                    } else {
+                        unfocused_height
+                    };
+                    let rect = Rect {
+                        x: 0,
+                        y: *y,
+                        width,
+                        height: h,
+                    };
+                    *y += h;
+                    Some(Some(rect))
+                })
+                .collect()
+        }
+        Layout::Horizontal => split(width, n, 1)
+            .into_iter()
+            .map(|(x, w)| {
+                Some(Rect {
+                    x,
+                    y: 0,
+                    width: w,
+                    height,
+                })
+            })
+            .collect(),
+        Layout::Grid => {
+            let columns = (1..=n).find(|c| c * c >= n).unwrap_or(n);
+            let rows = n.div_ceil(columns);
+            let xs = split(width, columns, 1);
+            // Rows are already separated by the status line of the windows below them.
+            let ys = split(height, rows, 0);
+            (0..n)
+                .map(|i| {
+                    let (x, w) = xs[i % columns];
+                    let (y, h) = ys[i / columns];
+                    Some(Rect {
+                        x,
+                        y,
+                        width: w,
+                        height: h,
+                    })
+                })
+                .collect()
+        }
+        Layout::Tabs => (0..n)
+            .map(|i| {
+                (i == focused).then_some(Rect {
+                    x: 0,
+                    y: 1,
+                    width,
+                    height: height.saturating_sub(1),
+                })
+            })
+            .collect(),
+    }
+}
@@ -0,0 +1,235 @@
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use termwiz::escape::parser::Parser;
+use termwiz::escape::{Action, ControlCode};
+
+use crate::timestamp;
+
+/// When log files are rotated and how many rotated files are kept.
+#[derive(Debug, Clone, Copy)]
+pub struct Rotation {
+    pub max_size: u64,
+    pub keep: usize,
+}
+
+/// A file that is renamed to `<path>.1` once it grows past the size limit, shifting older
+/// files to `.2`, `.3` and so on.
+struct LogFile {
+    path: PathBuf,
+    file: File,
+    size: u64,
+    rotation: Rotation,
+}
+
+impl LogFile {
+    fn open(path: PathBuf, rotation: Rotation) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let size = file.metadata()?.len();
+        Ok(Self {
+            path,
+            file,
+            size,
+            rotation,
+        })
+    }
+
+    fn write(&mut self, bytes: &[u8]) -> io::Result<()> {
+        if self.size > 0 && self.size + bytes.len() as u64 > self.rotation.max_size {
+            self.rotate()?;
+        }
+        self.file.write_all(bytes)?;
+        self.size += bytes.len() as u64;
+        Ok(())
+    }
+
+    fn rotated_path(&self, n: usize) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(format!(".{}", n));
+        path.into()
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        if self.rotation.keep == 0 {
+            self.file.set_len(0)?;
+        } else {
+            let oldest = self.rotated_path(self.rotation.keep);
+            if oldest.exists() {
+                std::fs::remove_file(oldest)?;
+            }
+            for n in (1..self.rotation.keep).rev() {
+                let from = self.rotated_path(n);
+                if from.exists() {
+                    std::fs::rename(from, self.rotated_path(n + 1))?;
+                }
+            }
+            std::fs::rename(&self.path, self.rotated_path(1))?;
+            self.file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&self.path)?;
+        }
+        self.size = 0;
+        Ok(())
+    }
+}
+
+/// Output of a process saved as is to `<name>.raw.log`, and as plain text without escape
+/// sequences to `<name>.log`, each line preceded by the time it started to be printed.
+pub struct ProcessLog {
+    raw: LogFile,
+    text: LogFile,
+    parser: Parser,
+    /// Text of the current line.
+    line: String,
+    /// When the current line started to be printed.
+    line_time: Option<SystemTime>,
+    /// A carriage return was received, so the next character starts over the line.
+    carriage_return: bool,
+}
+
+impl ProcessLog {
+    pub fn open(dir: &Path, name: &str, rotation: Rotation) -> io::Result<Self> {
+        std::fs::create_dir_all(dir)?;
+        Ok(Self {
+            raw: LogFile::open(dir.join(format!("{}.raw.log", name)), rotation)?,
+            text: LogFile::open(dir.join(format!("{}.log", name)), rotation)?,
+            parser: Parser::new(),
+            line: String::new(),
+            line_time: None,
+            carriage_return: false,
+        })
+    }
+
+    pub fn write(&mut self, bytes: &[u8]) -> io::Result<()> {
+        self.raw.write(bytes)?;
+
+        let now = SystemTime::now();
+        let mut lines = Vec::new();
+        let line = &mut self.line;
+        let line_time = &mut self.line_time;
+        let carriage_return = &mut self.carriage_return;
+        self.parser.parse(bytes, |action| match action {
+            Action::Print(c) => print(line, carriage_return, c.encode_utf8(&mut [0; 4])),
+            Action::PrintString(s) => print(line, carriage_return, &s),
+            Action::Control(ControlCode::HorizontalTab) => print(line, carriage_return, "\t"),
+            Action::Control(ControlCode::Backspace) => {
+                line.pop();
+            }
+            Action::Control(ControlCode::CarriageReturn) => *carriage_return = true,
+            Action::Control(ControlCode::LineFeed) => {
+                *carriage_return = false;
+                lines.push((line_time.take().unwrap_or(now), std::mem::take(line)));
+            }
+            _ => {}
+        });
+        if !self.line.is_empty() {
+            self.line_time.get_or_insert(now);
+        }
+
+        lines
+            .into_iter()
+            .try_for_each(|(time, line)| self.write_line(time, &line))
+    }
+
+    fn write_line(&mut self, time: SystemTime, line: &str) -> io::Result<()> {
+        let line = format!("{} {}\n", timestamp::format_local(time, true), line);
+        self.text.write(line.as_bytes())
+    }
+}
+
+fn print(line: &mut String, carriage_return: &mut bool, s: &str) {
+    if std::mem::take(carriage_return) {
+        line.clear();
+    }
+    line.push_str(s);
+}
+
+impl Drop for ProcessLog {
+    fn drop(&mut self) {
+        if !self.line.is_empty() {
+            let time = self.line_time.unwrap_or_else(SystemTime::now);
+            let line = std::mem::take(&mut self.line);
+            if let Err(e) = self.write_line(time, &line) {
+                log::error!("log error: {}", e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!(
+            "sudare-logfile-test-{}-{}",
+            name,
+            std::process::id()
+        ));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn read(path: PathBuf) -> Option<String> {
+        std::fs::read_to_string(path).ok()
+    }
+
+    #[test]
+    fn rotates_past_the_size_limit_and_keeps_the_newest_files() {
+        let dir = temp_dir("rotate");
+        let path = dir.join("web.log");
+        let rotation = Rotation {
+            max_size: 10,
+            keep: 2,
+        };
+        let mut log = LogFile::open(path.clone(), rotation).unwrap();
+        for line in [
+            "one\n", "two\n", "three\n", "four\n", "five\n", "six\n", "seven\n",
+        ] {
+            log.write(line.as_bytes()).unwrap();
+        }
+        // Up to the limit goes to the same file, and `one` and `two` were dropped.
+        assert_eq!(read(path.clone()).as_deref(), Some("six\nseven\n"));
+        assert_eq!(read(log.rotated_path(1)).as_deref(), Some("four\nfive\n"));
+        assert_eq!(read(log.rotated_path(2)).as_deref(), Some("three\n"));
+        assert_eq!(read(log.rotated_path(3)), None);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn counts_what_was_logged_before() {
+        let dir = temp_dir("reopen");
+        let path = dir.join("web.log");
+        std::fs::write(&path, "earlier\n").unwrap();
+        let rotation = Rotation {
+            max_size: 10,
+            keep: 1,
+        };
+        let mut log = LogFile::open(path.clone(), rotation).unwrap();
+        log.write(b"now\n").unwrap();
+        assert_eq!(read(path.clone()).as_deref(), Some("now\n"));
+        assert_eq!(read(log.rotated_path(1)).as_deref(), Some("earlier\n"));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn truncates_when_keeping_no_rotated_files() {
+        let dir = temp_dir("truncate");
+        let path = dir.join("web.log");
+        let rotation = Rotation {
+            max_size: 4,
+            keep: 0,
+        };
+        let mut log = LogFile::open(path.clone(), rotation).unwrap();
+        // A write larger than the limit goes to an empty file as is.
+        log.write(b"first line\n").unwrap();
+        log.write(b"two\n").unwrap();
+        assert_eq!(read(path.clone()).as_deref(), Some("two\n"));
+        assert_eq!(read(log.rotated_path(1)), None);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
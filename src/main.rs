@@ -1,129 +1,271 @@
+mod attach;
+mod control;
+mod copy;
+mod ctl;
+mod dotenv;
+mod history;
+mod keymap;
+mod layout;
+mod logfile;
+mod notify;
+mod procfile;
+mod run;
+mod search;
+mod timestamp;
+
 use std::collections::BTreeMap;
 use std::fmt::Debug;
 use std::fs::File;
-use std::io::{BufRead, BufReader, BufWriter, Read};
+use std::io::{BufReader, BufWriter, Read, Write};
 use std::ops::Range;
 use std::path::{Path, PathBuf};
 use std::string::ToString;
 use std::sync::mpsc::{Receiver, TryRecvError};
-use std::sync::{mpsc, Arc};
-use std::thread::{sleep, JoinHandle};
-use std::time::Duration;
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread::sleep;
+use std::time::{Duration, Instant, SystemTime};
 use std::{io, thread};
 
 use portable_pty::{
-    Child, CommandBuilder, ExitStatus, NativePtySystem, PtyPair, PtySize, PtySystem,
+    Child, CommandBuilder, ExitStatus, MasterPty, NativePtySystem, PtyPair, PtySize, PtySystem,
 };
 use regex::Regex;
 use serde::{Deserialize, Serialize};
-use termwiz::caps::{Capabilities, ProbeHints};
-use termwiz::cell::{AttributeChange, CellAttributes};
+use serde_json::{json, Value};
+use termwiz::cell::{AttributeChange, CellAttributes, Intensity};
 use termwiz::color::{AnsiColor, ColorAttribute};
-use termwiz::input::{InputEvent, KeyCode, KeyEvent};
+use termwiz::input::{InputEvent, KeyCode, KeyEvent, Modifiers};
 use termwiz::surface::{Change, Position, SequenceNo, Surface};
 use termwiz::terminal::buffered::BufferedTerminal;
-use termwiz::terminal::{new_terminal, Terminal};
+use termwiz::terminal::{Terminal, UnixTerminal};
 use termwiz::Error;
 use wezterm_term::color::ColorPalette;
-use wezterm_term::{ScrollbackOrVisibleRowIndex, TerminalConfiguration, TerminalSize};
-
-type Procfile = Vec<ProcessGroup>;
-
-fn parse_procfile(path: &Path) -> std::io::Result<Procfile> {
-    let re: Regex = Regex::new(r"^(.+)\[(.+)\]$").unwrap();
-    let reader = BufReader::new(File::open(path)?);
-    let (ordered, map) = reader
-        .lines()
-        .map(|l| l.unwrap())
-        .filter(|l| !l.starts_with("#") || l.contains(":"))
-        .map(|l| {
-            let (title, cmd) = {
-                let mut it = l.splitn(2, ":");
-                (it.next().unwrap(), it.next().unwrap())
-            };
-            let (a, b) = re
-                .captures(title)
-                .map(|cap| (cap.get(1).unwrap().as_str(), cap.get(2).unwrap().as_str()))
-                .unwrap_or((title, "default"));
-            (a.to_string(), b.to_string(), cmd.trim().to_string())
-        })
-        .fold(
-            (
-                Vec::<String>::new(),
-                BTreeMap::<String, Vec<(String, String)>>::new(),
-            ),
-            |mut acc, (a, b, c)| {
-                if !acc.0.contains(&a) {
-                    acc.0.push(a.clone());
-                }
-                acc.1.entry(a).or_default().push((b, c));
-                acc
-            },
-        );
-    let r: Procfile = ordered
-        .iter()
-        .map(|title| (title, map.get(title).unwrap()))
-        .map(|(title, members)| ProcessGroup {
-            title: title.clone(),
-            members: vec![Process::Null]
-                .into_iter()
-                .chain(members.iter().map(|(label, cmd)| Process::Command {
-                    label: label.clone(),
-                    argv: cmd.clone(),
-                }))
-                .collect(),
-        })
-        .collect();
-    Ok(r)
+use wezterm_term::{Line, StableRowIndex, TerminalConfiguration, TerminalSize};
+
+use control::Params;
+use copy::{CopyMode, SelectionKind, Yank};
+use history::History;
+use keymap::{Action, Keymap, Lookup};
+use layout::{Layout, Rect};
+use logfile::{ProcessLog, Rotation};
+use notify::{Notification, Notify};
+use procfile::{
+    check_procfile, parse_procfile, Process, ProcessGroup, ProcessOptions, Procfile, RestartPolicy,
+    Severity,
+};
+use search::{Search, SearchPrompt};
+
+impl ProcessGroup {
+    /// Environment of a member: the dotenv variables plus `PORT`, which is shared by the
+    /// members of a group unless they run concurrently.
+    fn env(&self, index: usize, settings: &Settings) -> dotenv::Env {
+        let mut env = settings.env.clone();
+        if let Some(port) = self.port(index, settings) {
+            env.insert("PORT".to_string(), port.to_string());
+        }
+        env
+    }
+
+    /// `PORT` of a member, or `None` when it would be past the last port.
+    fn port(&self, index: usize, settings: &Settings) -> Option<u16> {
+        let offset = if self.concurrent {
+            index.saturating_sub(1)
+        } else {
+            0
+        };
+        let port =
+            settings.base_port() as u64 + self.index as u64 * PORT_STEP as u64 + offset as u64;
+        u16::try_from(port).ok()
+    }
+
+    /// Log files of a member, when `--log-dir` is given.
+    fn log(&self, index: usize, settings: &Settings) -> Option<ProcessLog> {
+        let dir = settings.log_dir.as_ref()?;
+        let name = format!("{}-{}", self.title, self.members.get(index)?.label());
+        ProcessLog::open(dir, &name, settings.log_rotation)
+            .map_err(|e| log::error!("log error: {}", e))
+            .ok()
+    }
 }
 
-#[derive(Debug, Clone)]
-struct ProcessGroup {
-    title: String,
-    members: Vec<Process>,
+impl ProcessOptions {
+    fn stop_policy(&self, settings: &Settings) -> StopPolicy {
+        StopPolicy {
+            signal: self.stop_signal.unwrap_or(libc::SIGTERM),
+            timeout: self.stop_timeout.unwrap_or(settings.stop_timeout),
+        }
+    }
 }
 
-#[derive(Debug, Clone)]
-enum Process {
-    Null,
-    Command { label: String, argv: String },
+const DEFAULT_MAX_RETRIES: u32 = 10;
+const RESTART_BACKOFF_MIN: Duration = Duration::from_secs(1);
+const RESTART_BACKOFF_MAX: Duration = Duration::from_secs(60);
+
+/// Restart bookkeeping of a group member.
+#[derive(Debug, Default)]
+struct Restart {
+    count: u32,
+    scheduled_at: Option<Instant>,
 }
 
-const DEFAULT_TITLE: &str = "disable";
+/// The restart count of a member that exited after running for `run_time`, and the delay
+/// before restarting it. A member that ran for a while is considered healthy again and starts
+/// over from the shortest delay.
+fn restart_backoff(count: u32, run_time: Duration) -> (u32, Duration) {
+    let count = if run_time >= RESTART_BACKOFF_MAX {
+        0
+    } else {
+        count
+    };
+    let delay = RESTART_BACKOFF_MIN
+        .saturating_mul(2u32.saturating_pow(count))
+        .min(RESTART_BACKOFF_MAX);
+    (count, delay)
+}
 
-impl Process {
-    pub fn label(&self) -> String {
-        match self {
-            Process::Null => DEFAULT_TITLE.to_string(),
-            Process::Command { label, argv: _ } => label.to_string(),
+/// Runtime settings given on the command line.
+#[derive(Debug, Clone)]
+struct Settings {
+    stop_timeout: Duration,
+    /// Dotenv files given with `--env`, loaded after the `.env` next to the Procfile.
+    env_files: Vec<PathBuf>,
+    /// Variables loaded from the dotenv files, passed to every process.
+    env: dotenv::Env,
+    base_port: Option<u16>,
+    /// Lines kept in memory for scrolling back, unless set for the process.
+    scroll_back: usize,
+    /// Save lines that no longer fit in the scrollback to a file for every process.
+    history: bool,
+    /// Where history files are saved.
+    history_dir: PathBuf,
+    /// Where the output of every process is logged, if anywhere.
+    log_dir: Option<PathBuf>,
+    log_rotation: Rotation,
+    layout: Layout,
+    /// Output lines that flag a window as having errors, if any.
+    error_pattern: Option<Regex>,
+    notify: Notify,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            stop_timeout: Duration::from_secs(5),
+            env_files: Vec::new(),
+            env: dotenv::Env::new(),
+            base_port: None,
+            scroll_back: DEFAULT_SCROLL_BACK,
+            history: false,
+            history_dir: PathBuf::new(),
+            log_dir: None,
+            log_rotation: Rotation {
+                max_size: DEFAULT_LOG_SIZE_MB * 1024 * 1024,
+                keep: DEFAULT_LOG_KEEP,
+            },
+            layout: Layout::default(),
+            error_pattern: Some(Regex::new(DEFAULT_ERROR_PATTERN).unwrap()),
+            notify: Notify::default(),
         }
     }
 }
 
+const DEFAULT_SCROLL_BACK: usize = 1000;
+const DEFAULT_LOG_SIZE_MB: u64 = 10;
+const DEFAULT_LOG_KEEP: usize = 5;
+const DEFAULT_ERROR_PATTERN: &str = "ERROR|panic|Traceback";
+const DEFAULT_BASE_PORT: u16 = 5000;
+const PORT_STEP: u16 = 100;
+
+impl Settings {
+    /// `--port`, otherwise `PORT` from the dotenv files or sudare's environment, like foreman.
+    fn base_port(&self) -> u16 {
+        self.base_port
+            .or_else(|| {
+                self.env
+                    .get("PORT")
+                    .cloned()
+                    .or_else(|| std::env::var("PORT").ok())
+                    .and_then(|it| it.parse().ok())
+            })
+            .unwrap_or(DEFAULT_BASE_PORT)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum InputMode {
+    Normal,
+    Passthrough,
+}
+
+/// Size of the screen until a client attaches.
+const DETACHED_DIMENSION: (usize, usize) = (80, 24);
+
+/// Rows returned by the `logs` request by default.
+const DEFAULT_LOGS_TAIL: usize = 100;
+
+/// Rows of the history searched at once.
+const SEARCH_CHUNK_ROWS: StableRowIndex = 10_000;
+
+/// How long a message stays at the bottom of the screen.
+const MESSAGE_DURATION: Duration = Duration::from_secs(3);
+
 #[derive(Serialize, Deserialize, Debug)]
 struct SavedState {
     focused_group: String,
     active_processes: BTreeMap<String, String>,
+    /// Members running in concurrent groups.
+    #[serde(default)]
+    running_processes: BTreeMap<String, Vec<String>>,
 }
 
 struct UiState {
     procfile_hash: String,
+    settings: Settings,
     focused_window_index: usize,
     windows: Vec<UiWindow>,
     surface: Surface,
     min_window_height: usize,
     repaint: bool,
+    input_mode: InputMode,
+    keymap: Keymap,
+    /// The key bindings are shown over the windows.
+    show_help: bool,
+    search_prompt: Option<SearchPrompt>,
+    show_timestamps: bool,
+    layout: Layout,
+    /// Only the focused window is shown, with a summary of the others.
+    zoomed: bool,
+    /// Feedback shown at the bottom of the screen for a while.
+    message: Option<(String, Instant)>,
+    /// Every process is being stopped to exit, and none may start again.
+    quitting: bool,
 }
 
 impl UiState {
-    pub fn new(procfile_hash: String, procfile: Procfile, dimension: (usize, usize)) -> UiState {
+    pub fn new(
+        procfile_hash: String,
+        settings: Settings,
+        keymap: Keymap,
+        procfile: Procfile,
+        dimension: (usize, usize),
+    ) -> UiState {
         UiState {
             procfile_hash,
+            layout: settings.layout,
+            settings,
             focused_window_index: 0,
             windows: procfile.into_iter().map(|it| UiWindow::new(it)).collect(),
             surface: Surface::new(dimension.0, dimension.1),
             min_window_height: 2,
             repaint: true,
+            input_mode: InputMode::Normal,
+            keymap,
+            show_help: false,
+            search_prompt: None,
+            show_timestamps: false,
+            zoomed: false,
+            message: None,
+            quitting: false,
         }
     }
 
@@ -154,11 +296,56 @@ impl UiState {
 
     pub fn select_process(&mut self, pty_system: &dyn PtySystem, index: usize) {
         if let Some(group) = self.windows.get_mut(self.focused_window_index) {
-            group.set_active(pty_system, self.surface.dimensions(), index);
+            group.set_active(pty_system, self.surface.dimensions(), index, &self.settings);
+        }
+        self.repaint = true;
+    }
+
+    pub fn toggle_process(&mut self, pty_system: &dyn PtySystem) {
+        if let Some(group) = self.windows.get_mut(self.focused_window_index) {
+            group.toggle_active(pty_system, self.surface.dimensions(), &self.settings);
+        }
+        self.repaint = true;
+    }
+
+    pub fn restart_process(&mut self, pty_system: &dyn PtySystem) {
+        if let Some(group) = self.windows.get_mut(self.focused_window_index) {
+            let index = group.active_process_index;
+            group.restart(pty_system, self.surface.dimensions(), index, &self.settings);
         }
         self.repaint = true;
     }
 
+    pub fn supervise(&mut self, pty_system: &dyn PtySystem) {
+        if self.quitting {
+            return;
+        }
+        let settings = &self.settings;
+        self.windows
+            .iter_mut()
+            .for_each(|it| it.supervise(pty_system, settings));
+    }
+
+    /// Feeds the output of every process into its terminal, whether a client is attached or not.
+    pub fn advance(&mut self) {
+        self.windows.iter_mut().for_each(|it| it.advance());
+    }
+
+    /// Asks every running process to stop for good. Call `is_stopped` to see when they are all
+    /// gone.
+    pub fn quit(&mut self) {
+        self.quitting = true;
+        self.windows.iter_mut().for_each(|it| it.stop());
+    }
+
+    pub fn is_quitting(&self) -> bool {
+        self.quitting
+    }
+
+    pub fn is_stopped(&self) -> bool {
+        self.windows.iter().all(|it| it.is_stopped())
+    }
+
     pub fn scroll_up(&mut self) {
         if let Some(group) = self.windows.get_mut(self.focused_window_index) {
             group.scroll_up();
@@ -173,28 +360,532 @@ impl UiState {
         self.repaint = true;
     }
 
+    pub fn toggle_zoom(&mut self) {
+        self.zoomed = !self.zoomed;
+        self.repaint = true;
+    }
+
+    /// Draws a line with the title of every group at the top of the screen.
+    fn render_tabs(&self, screen: &mut Surface) {
+        let mut changes = vec![Change::CursorPosition {
+            x: Position::Absolute(0),
+            y: Position::Absolute(0),
+        }];
+        self.windows.iter().enumerate().for_each(|(i, it)| {
+            let color = if i == self.focused_window_index {
+                AnsiColor::Fuchsia
+            } else {
+                it.activity().color()
+            };
+            changes.push(Change::Attribute(AttributeChange::Background(
+                ColorAttribute::from(color),
+            )));
+            changes.push(Change::Attribute(AttributeChange::Foreground(
+                ColorAttribute::from(AnsiColor::White),
+            )));
+            changes.push(Change::Text(format!(
+                " {}:{} ",
+                i + 1,
+                it.process_group.title
+            )));
+            changes.push(Change::AllAttributes(CellAttributes::default()));
+            changes.push(Change::Text(" ".to_string()));
+        });
+        changes.push(Change::ClearToEndOfLine(ColorAttribute::Default));
+        screen.add_changes(changes);
+    }
+
+    pub fn cycle_layout(&mut self) {
+        self.layout = self.layout.next();
+        self.zoomed = false;
+        self.message = Some((format!("Layout: {}", self.layout), Instant::now()));
+        self.repaint = true;
+    }
+
+    /// Draws a line with the state of every group but the focused one.
+    fn render_summary(&self, screen: &mut Surface, y: usize) {
+        let mut changes = vec![
+            Change::CursorPosition {
+                x: Position::Absolute(0),
+                y: Position::Absolute(y),
+            },
+            Change::AllAttributes(CellAttributes::default()),
+        ];
+        self.windows
+            .iter()
+            .enumerate()
+            .filter(|(i, _)| *i != self.focused_window_index)
+            .for_each(|(_, it)| {
+                let (text, color) = it.summary();
+                changes.push(Change::Attribute(AttributeChange::Foreground(
+                    ColorAttribute::from(color),
+                )));
+                changes.push(Change::Text(format!(" {} ", text)));
+            });
+        changes.push(Change::ClearToEndOfLine(ColorAttribute::Default));
+        screen.add_changes(changes);
+    }
+
+    pub fn toggle_timestamps(&mut self) {
+        self.show_timestamps = !self.show_timestamps;
+        self.repaint = true;
+    }
+
+    pub fn toggle_input_mode(&mut self) {
+        self.input_mode = match self.input_mode {
+            InputMode::Normal => InputMode::Passthrough,
+            InputMode::Passthrough => InputMode::Normal,
+        };
+        self.repaint = true;
+    }
+
+    pub fn is_help_open(&self) -> bool {
+        self.show_help
+    }
+
+    pub fn close_help(&mut self) {
+        self.show_help = false;
+        self.repaint = true;
+    }
+
+    /// Draws a box in the middle of the screen with every key binding and its action.
+    fn render_help(&self, screen: &mut Surface) {
+        let (width, height) = screen.dimensions();
+        let help = self.keymap.help();
+        let keys_width = help
+            .iter()
+            .map(|(k, _)| k.chars().count())
+            .max()
+            .unwrap_or(0);
+        let lines = help
+            .iter()
+            .map(|(keys, action)| format!("{:w$}  {}", keys, action.description(), w = keys_width))
+            .chain(["".to_string(), "Press any key to close".to_string()])
+            .collect::<Vec<_>>();
+
+        // Inside the border, with a space on each side.
+        let inner_width = lines
+            .iter()
+            .map(|it| it.chars().count())
+            .max()
+            .unwrap_or(0)
+            .min(width.saturating_sub(4));
+        let inner_height = lines.len().min(height.saturating_sub(2));
+        let (w, h) = (inner_width + 4, inner_height + 2);
+        if w > width || h > height {
+            return;
+        }
+
+        let title = " Key bindings ";
+        let mut changes = vec![Change::Text(format!(
+            "┌─{}{}┐",
+            title,
+            "─".repeat((w - 3).saturating_sub(title.chars().count()))
+        ))];
+        lines.iter().take(inner_height).for_each(|line| {
+            let line = line.chars().take(inner_width).collect::<String>();
+            changes.push(Change::Text(format!(
+                "\r\n│ {:w$} │",
+                line,
+                w = inner_width
+            )));
+        });
+        changes.push(Change::Text(format!("\r\n└{}┘", "─".repeat(w - 2))));
+
+        let mut surface = Surface::new(w, h);
+        surface.add_changes(changes);
+        screen.draw_from_screen(&surface, (width - w) / 2, (height - h) / 2);
+    }
+
+    pub fn is_search_prompt_open(&self) -> bool {
+        self.search_prompt.is_some()
+    }
+
+    pub fn open_search_prompt(&mut self) {
+        self.search_prompt = Some(SearchPrompt::default());
+    }
+
+    /// Edits the search query. Enter searches the focused window, an empty query clears the
+    /// search, and Esc closes the prompt.
+    pub fn search_prompt_key(&mut self, key: &KeyEvent) {
+        let prompt = match self.search_prompt.as_mut() {
+            Some(prompt) => prompt,
+            None => return,
+        };
+        match (key.key, key.modifiers) {
+            (KeyCode::Escape, _) => self.search_prompt = None,
+            (KeyCode::Enter, _) => match prompt.compile() {
+                Ok(regex) => {
+                    self.search_prompt = None;
+                    if let Some(group) = self.windows.get_mut(self.focused_window_index) {
+                        group.set_search(regex);
+                    }
+                }
+                Err(e) => prompt.error = Some(e.to_string().lines().last().unwrap_or("").into()),
+            },
+            (KeyCode::Backspace, _) => {
+                prompt.input.pop();
+                prompt.error = None;
+            }
+            (KeyCode::Char('r'), Modifiers::CTRL) => {
+                prompt.regex = !prompt.regex;
+                prompt.error = None;
+            }
+            (KeyCode::Char(c), Modifiers::NONE) | (KeyCode::Char(c), Modifiers::SHIFT) => {
+                prompt.input.push(c);
+                prompt.error = None;
+            }
+            _ => {}
+        }
+        self.repaint = true;
+    }
+
+    pub fn is_searching(&self) -> bool {
+        self.windows
+            .get(self.focused_window_index)
+            .map(|group| group.is_searching())
+            .unwrap_or(false)
+    }
+
+    /// Whether `key` moves between the matches of the search shown in the focused window, or
+    /// clears it. Other keys keep their bindings while searching.
+    pub fn is_search_key(&self, key: &KeyEvent) -> bool {
+        self.is_searching()
+            && matches!(
+                (key.key, key.modifiers),
+                (KeyCode::Escape, Modifiers::NONE)
+                    | (KeyCode::Char('n'), Modifiers::NONE)
+                    | (KeyCode::Char('N'), Modifiers::NONE | Modifiers::SHIFT)
+            )
+    }
+
+    /// `n` jumps to the previous (older) match in the focused window, `N` to the next one and
+    /// Esc clears the search.
+    pub fn search_key(&mut self, key: &KeyEvent) {
+        if let Some(group) = self.windows.get_mut(self.focused_window_index) {
+            match key.key {
+                KeyCode::Char('n') => group.search_step(true),
+                KeyCode::Char('N') => group.search_step(false),
+                _ => group.set_search(None),
+            }
+        }
+        self.repaint = true;
+    }
+
+    pub fn enter_copy_mode(&mut self) {
+        if let Some(group) = self.windows.get_mut(self.focused_window_index) {
+            group.enter_copy_mode();
+        }
+    }
+
+    pub fn is_copy_mode(&self) -> bool {
+        self.windows
+            .get(self.focused_window_index)
+            .map(|group| group.is_copy_mode())
+            .unwrap_or(false)
+    }
+
+    /// Handles a key in copy mode, exporting the selection when it is yanked. The clipboard
+    /// is set through `terminal`; the selection is written to a file instead if that fails.
+    pub fn copy_mode_key(&mut self, terminal: &mut impl Terminal, key: &KeyEvent) {
+        let yank = self
+            .windows
+            .get_mut(self.focused_window_index)
+            .and_then(|group| group.copy_mode_key(key));
+        let message = match yank {
+            Some(Yank::Clipboard(text)) => match copy::set_clipboard(terminal, &text) {
+                Ok(()) => format!("Copied {} line(s) to the clipboard", text.lines().count()),
+                Err(e) => {
+                    log::error!("set_clipboard error: {}", e);
+                    self.save_selection(&text)
+                }
+            },
+            Some(Yank::File(text)) => self.save_selection(&text),
+            None => {
+                self.repaint = true;
+                return;
+            }
+        };
+        self.message = Some((message, Instant::now()));
+        self.repaint = true;
+    }
+
+    fn save_selection(&self, text: &str) -> String {
+        let path = UiState::cache_dir().join("selection.txt");
+        match copy::write_file(&path, text) {
+            Ok(()) => format!("Saved selection to {}", path.display()),
+            Err(e) => format!("Failed to save selection to {}: {}", path.display(), e),
+        }
+    }
+
+    fn window_index(&self, title: &str) -> Result<usize, control::Error> {
+        self.windows
+            .iter()
+            .position(|it| it.process_group.title == title)
+            .ok_or_else(|| control::Error::invalid_params(format!("no group `{}`", title)))
+    }
+
+    /// Runs a request of the control socket.
+    pub fn handle_request(
+        &mut self,
+        pty_system: &dyn PtySystem,
+        request: &control::Request,
+    ) -> Result<Value, control::Error> {
+        let params = Params(&request.params);
+        let dimension = self.surface.dimensions();
+        let result = match request.method.as_str() {
+            "list" => self
+                .windows
+                .iter()
+                .enumerate()
+                .map(|(i, it)| it.describe(i == self.focused_window_index))
+                .collect(),
+            "status" => match params.opt_str("group")? {
+                Some(title) => self.windows[self.window_index(title)?].status(),
+                None => self.windows.iter().map(|it| it.status()).collect(),
+            },
+            method @ ("start" | "stop" | "restart" | "select-member") => {
+                if self.quitting && method != "stop" {
+                    return Err(control::Error::server("the session is quitting"));
+                }
+                let index = self.window_index(params.str("group")?)?;
+                let window = &mut self.windows[index];
+                let member = match params.opt_value("member") {
+                    Some(member) => window.member_index(member)?,
+                    None if method == "select-member" => {
+                        return Err(control::Error::invalid_params("missing `member`"))
+                    }
+                    None => window.active_process_index,
+                };
+                let settings = &self.settings;
+                match method {
+                    "start" => window.start_member(pty_system, dimension, member, settings),
+                    "stop" => window.stop_member(member),
+                    "restart" => window.restart(pty_system, dimension, member, settings),
+                    _ => window.set_active(pty_system, dimension, member, settings),
+                }
+                window.status()
+            }
+            "logs" => {
+                let index = self.window_index(params.str("group")?)?;
+                let window = &self.windows[index];
+                let member = match params.opt_value("member") {
+                    Some(member) => window.member_index(member)?,
+                    None => window.active_process_index,
+                };
+                let tail = match params.opt_value("tail") {
+                    Some(tail) => tail
+                        .as_u64()
+                        .ok_or_else(|| control::Error::invalid_params("`tail` must be a number"))?
+                        as usize,
+                    None => DEFAULT_LOGS_TAIL,
+                };
+                match window.pty_terminals.get(&member) {
+                    Some(t) => json!(t.tail(tail)),
+                    None => {
+                        return Err(control::Error::server(format!(
+                            "`{}` member {} has not been started",
+                            window.process_group.title, member
+                        )))
+                    }
+                }
+            }
+            "focus-group" => {
+                let index = self.window_index(params.str("group")?)?;
+                if let Some(group) = self.windows.get_mut(self.focused_window_index) {
+                    group.reset_scroll();
+                }
+                self.focused_window_index = index;
+                self.windows[index].describe(true)
+            }
+            "send-keys" => {
+                let index = self.window_index(params.str("group")?)?;
+                let window = &mut self.windows[index];
+                let keys = match params.opt_value("keys") {
+                    Some(Value::String(s)) => vec![s.as_str()],
+                    Some(Value::Array(items)) => items
+                        .iter()
+                        .map(|it| it.as_str())
+                        .collect::<Option<Vec<_>>>()
+                        .ok_or_else(|| control::Error::invalid_params("`keys` must be strings"))?,
+                    _ => return Err(control::Error::invalid_params("missing `keys`")),
+                };
+                if window.active_terminal().is_none() {
+                    return Err(control::Error::server(format!(
+                        "`{}` is not running",
+                        window.process_group.title
+                    )));
+                }
+                // Names of keys like `ENTER` or `C-c` are typed as such, anything else as text.
+                for item in keys {
+                    match keymap::parse_key(item) {
+                        Ok(key) if item.chars().count() > 1 => window.send_key(&key),
+                        _ => window.send_text(item),
+                    }
+                }
+                Value::Null
+            }
+            method => return Err(control::Error::method_not_found(method)),
+        };
+        self.repaint = true;
+        Ok(result)
+    }
+
+    /// Notifies what happened to processes since the last call, through the terminal of the
+    /// attached client if any and the notify command.
+    pub fn send_notifications(&mut self, mut terminal: Option<&mut UnixTerminal>) {
+        let notify = &self.settings.notify;
+        let notifications = self
+            .windows
+            .iter_mut()
+            .flat_map(|it| it.take_notifications(notify))
+            .collect::<Vec<_>>();
+        for notification in notifications.iter() {
+            if let Some(terminal) = terminal.as_deref_mut() {
+                if let Err(e) = notify::send(terminal, notification) {
+                    log::error!("notify error: {}", e);
+                }
+            }
+            if let Some(command) = notify.command.as_ref() {
+                if let Err(e) = notify::run_command(command, notification) {
+                    log::error!("notify command error: {}", e);
+                }
+            }
+        }
+    }
+
+    /// Runs an action bound to a key, except quitting and detaching which are up to the caller.
+    pub fn perform(&mut self, pty_system: &dyn PtySystem, action: Action) {
+        match action {
+            Action::Quit | Action::Detach => {}
+            // Nothing may start while quitting, or the quit would wait for it forever.
+            Action::SelectMember(_) | Action::ToggleMember | Action::Restart if self.quitting => {}
+            Action::NextGroup => self.next_window(),
+            Action::PrevGroup => self.previous_window(),
+            Action::SelectMember(index) => self.select_process(pty_system, index),
+            Action::ToggleMember => self.toggle_process(pty_system),
+            Action::Restart => self.restart_process(pty_system),
+            Action::ScrollUp => self.scroll_up(),
+            Action::ScrollDown => self.scroll_down(),
+            Action::Search => self.open_search_prompt(),
+            Action::CopyMode => self.enter_copy_mode(),
+            Action::ToggleTimestamps => self.toggle_timestamps(),
+            Action::Zoom => self.toggle_zoom(),
+            Action::CycleLayout => self.cycle_layout(),
+            Action::InsertMode => self.toggle_input_mode(),
+            Action::Help => self.show_help = true,
+        }
+        self.repaint = true;
+    }
+
+    pub fn send_key(&mut self, key: &KeyEvent) {
+        if let Some(group) = self.windows.get_mut(self.focused_window_index) {
+            group.send_key(key);
+        }
+    }
+
+    pub fn send_paste(&mut self, text: &str) {
+        if let Some(group) = self.windows.get_mut(self.focused_window_index) {
+            group.send_paste(text);
+        }
+    }
+
     pub fn render_to_screen(&mut self, screen: &mut Surface) {
         let (width, height) = screen.dimensions();
+        // Keep track of the screen size so that newly spawned processes start with it.
+        if self.surface.dimensions() != (width, height) {
+            self.surface.resize(width, height);
+        }
 
         // Render from scratch into a fresh screen buffer
         let mut alt_screen = Surface::new(width, height);
 
-        let unfocused_height = self.windows.len().saturating_sub(1) * (1 + self.min_window_height);
-        let focused_height = height - unfocused_height;
+        let n = self.windows.len();
+        let focused_window_index = self.focused_window_index;
+        let summary_height = if self.zoomed && n > 1 { 1 } else { 0 };
+        let rects = if self.zoomed {
+            (0..n)
+                .map(|i| {
+                    (i == focused_window_index).then_some(Rect {
+                        x: 0,
+                        y: 0,
+                        width,
+                        height: height.saturating_sub(summary_height),
+                    })
+                })
+                .collect()
+        } else {
+            layout::arrange(
+                self.layout,
+                n,
+                focused_window_index,
+                width,
+                height,
+                self.min_window_height,
+            )
+        };
 
-        self.windows
-            .iter_mut()
-            .enumerate()
-            .fold(0usize, |y, (i, it)| {
-                let focused = i == self.focused_window_index;
-                let h = if focused {
-                    focused_height
-                } else {
-                    1 + self.min_window_height
-                };
-                it.render(&mut alt_screen, width, y, h, focused);
-                y + h
-            });
+        let mut changes = vec![Change::Attribute(AttributeChange::Foreground(
+            ColorAttribute::from(AnsiColor::Grey),
+        ))];
+        for (i, (it, rect)) in self.windows.iter_mut().zip(rects).enumerate() {
+            let rect = match rect {
+                Some(rect) => rect,
+                None => {
+                    it.advance();
+                    continue;
+                }
+            };
+            let focused = i == focused_window_index;
+            let style = WindowStyle {
+                focused,
+                passthrough: focused && self.input_mode == InputMode::Passthrough,
+                timestamps: self.show_timestamps,
+            };
+            it.render(&mut alt_screen, rect, style);
+            // Separate windows side by side.
+            if rect.x > 0 {
+                (rect.y..rect.y + rect.height).for_each(|y| {
+                    changes.push(Change::CursorPosition {
+                        x: Position::Absolute(rect.x - 1),
+                        y: Position::Absolute(y),
+                    });
+                    changes.push(Change::Text("│".to_string()));
+                });
+            }
+        }
+        changes.push(Change::AllAttributes(CellAttributes::default()));
+        alt_screen.add_changes(changes);
+
+        if summary_height > 0 {
+            self.render_summary(&mut alt_screen, height - 1);
+        } else if self.layout == Layout::Tabs && !self.zoomed {
+            self.render_tabs(&mut alt_screen);
+        }
+
+        let bottom_line = match (&self.search_prompt, &self.message) {
+            (Some(prompt), _) => Some(prompt.to_string()),
+            (None, Some((message, at))) if at.elapsed() < MESSAGE_DURATION => Some(message.clone()),
+            _ if self.is_searching() => {
+                Some("n: older match, N: newer match, ESC: clear the search".to_string())
+            }
+            _ => None,
+        };
+        if let Some(text) = bottom_line {
+            alt_screen.add_changes(vec![
+                Change::CursorPosition {
+                    x: Position::Absolute(0),
+                    y: Position::Absolute(height.saturating_sub(1)),
+                },
+                Change::AllAttributes(CellAttributes::default()),
+                Change::Text(text),
+                Change::ClearToEndOfLine(ColorAttribute::Default),
+            ]);
+        }
+
+        if self.show_help {
+            self.render_help(&mut alt_screen);
+        }
 
         if self.repaint {
             screen.add_change(Change::ClearScreen(ColorAttribute::Default));
@@ -220,6 +911,19 @@ impl UiState {
             }
             acc
         });
+        let running_processes = self
+            .windows
+            .iter()
+            .filter(|it| it.process_group.concurrent)
+            .map(|it| {
+                let labels = it
+                    .running_indices()
+                    .into_iter()
+                    .filter_map(|i| it.process_group.members.get(i).map(|p| p.label()))
+                    .collect();
+                (it.process_group.title.clone(), labels)
+            })
+            .collect();
 
         let state = SavedState {
             focused_group: self
@@ -230,6 +934,7 @@ impl UiState {
                 .title
                 .clone(),
             active_processes,
+            running_processes,
         };
 
         std::fs::create_dir_all(UiState::cache_dir())?;
@@ -249,6 +954,7 @@ impl UiState {
             }
 
             let dim = self.surface.dimensions();
+            let settings = self.settings.clone();
             state.active_processes.iter().for_each(|(title, label)| {
                 if let Some((_, w)) = self.find_window_by_title(title) {
                     if let Some((i, _)) = w
@@ -258,10 +964,25 @@ impl UiState {
                         .enumerate()
                         .find(|(_, p)| p.label() == *label)
                     {
-                        w.set_active(pty_system, dim, i);
+                        w.set_active(pty_system, dim, i, &settings);
                     }
                 }
             });
+            state.running_processes.iter().for_each(|(title, labels)| {
+                if let Some((_, w)) = self.find_window_by_title(title) {
+                    let indices = w
+                        .process_group
+                        .members
+                        .iter()
+                        .enumerate()
+                        .filter(|(_, p)| labels.contains(&p.label()))
+                        .map(|(i, _)| i)
+                        .collect::<Vec<_>>();
+                    indices
+                        .into_iter()
+                        .for_each(|i| w.start(pty_system, dim, i, &settings));
+                }
+            });
 
             Ok(())
         } else {
@@ -283,18 +1004,107 @@ impl UiState {
     }
 }
 
-struct UiWindow {
-    process_group: ProcessGroup,
-    active_process_index: usize,
-    pty_terminal: Option<PtyTerminal>,
+/// What became of the active member of a group.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum RunState {
+    Running,
+    /// Not started, or stopped on request.
+    Stopped,
+    /// Exited successfully on its own.
+    Exited,
+    /// Exited with a failure on its own.
+    Crashed,
 }
 
-impl UiWindow {
-    pub fn new(process_group: ProcessGroup) -> Self {
-        Self {
-            process_group,
-            active_process_index: 0,
-            pty_terminal: None,
+impl RunState {
+    fn color(&self) -> AnsiColor {
+        match self {
+            RunState::Running => AnsiColor::Green,
+            RunState::Stopped => AnsiColor::Grey,
+            RunState::Exited => AnsiColor::Navy,
+            RunState::Crashed => AnsiColor::Red,
+        }
+    }
+}
+
+impl std::fmt::Display for RunState {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            RunState::Running => "running",
+            RunState::Stopped => "stopped",
+            RunState::Exited => "exited",
+            RunState::Crashed => "crashed",
+        })
+    }
+}
+
+/// Something that happened to a process that may be notified.
+#[derive(Debug, Clone)]
+enum ProcessEvent {
+    /// Exited on its own with this code.
+    Exited(u32),
+    /// Printed a line matching the notify pattern.
+    Matched(String),
+}
+
+/// Notifications of matching output are sent at most this often per process.
+const MATCH_NOTIFY_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Output of a window that has not been seen, from the least to the most important.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default)]
+enum Activity {
+    #[default]
+    None,
+    Output,
+    /// Output matching the error pattern.
+    Error,
+}
+
+impl Activity {
+    /// Status line color of an unfocused window.
+    fn color(&self) -> AnsiColor {
+        match self {
+            Activity::None => AnsiColor::Grey,
+            Activity::Output => AnsiColor::Olive,
+            Activity::Error => AnsiColor::Maroon,
+        }
+    }
+}
+
+/// How a window is drawn.
+#[derive(Debug, Clone, Copy)]
+struct WindowStyle {
+    focused: bool,
+    /// Keys are sent to the process.
+    passthrough: bool,
+    /// Show when each row was printed.
+    timestamps: bool,
+}
+
+struct UiWindow {
+    process_group: ProcessGroup,
+    active_process_index: usize,
+    /// Terminals of started members, keyed by member index.
+    pty_terminals: BTreeMap<usize, PtyTerminal>,
+    /// Replaced terminals whose processes are still shutting down.
+    stopping: Vec<PtyTerminal>,
+    restarts: BTreeMap<usize, Restart>,
+    /// Output since the window was last focused.
+    activity: Activity,
+    /// Events of members, by member index, until they are notified.
+    events: Vec<(usize, ProcessEvent)>,
+}
+
+impl UiWindow {
+    pub fn new(process_group: ProcessGroup) -> Self {
+        Self {
+            process_group,
+            active_process_index: 0,
+            pty_terminals: BTreeMap::new(),
+            stopping: Vec::new(),
+            restarts: BTreeMap::new(),
+            activity: Activity::None,
+            events: Vec::new(),
         }
     }
 
@@ -306,60 +1116,491 @@ impl UiWindow {
         }
     }
 
+    /// Indices of members whose processes are running and have not been asked to stop.
+    pub fn running_indices(&self) -> Vec<usize> {
+        self.pty_terminals
+            .iter()
+            .filter(|(_, t)| !t.is_stopped() && !t.is_stopping())
+            .map(|(i, _)| *i)
+            .collect()
+    }
+
+    fn active_terminal(&mut self) -> Option<&mut PtyTerminal> {
+        self.pty_terminals.get_mut(&self.active_process_index)
+    }
+
+    /// Displays the member at `index`. In a concurrent group the other members keep running,
+    /// otherwise they are stopped and the selected member is (re)started.
     pub fn set_active(
         &mut self,
         pty_system: &dyn PtySystem,
         dimension: (usize, usize),
         index: usize,
+        settings: &Settings,
     ) {
-        if let Some(process) = self.process_group.members.get(index) {
-            // if let Some(t) = &mut self.pty_terminal {
-            //     t.pty_process.kill().unwrap();
-            // }
-            self.pty_terminal = None;
+        if index >= self.process_group.members.len() {
+            return;
+        }
 
-            self.active_process_index = index;
+        if let Some(t) = self.active_terminal() {
+            t.reset_scroll();
+        }
+        self.active_process_index = index;
+
+        if !self.process_group.concurrent {
+            let indices = self.pty_terminals.keys().copied().collect::<Vec<_>>();
+            indices.into_iter().for_each(|i| self.retire(i));
+            self.start(pty_system, dimension, index, settings);
+        }
+    }
+
+    /// Starts the displayed member if it is not running, otherwise stops it.
+    pub fn toggle_active(
+        &mut self,
+        pty_system: &dyn PtySystem,
+        dimension: (usize, usize),
+        settings: &Settings,
+    ) {
+        let index = self.active_process_index;
+        if self.running_indices().contains(&index) {
+            if let Some(t) = self.pty_terminals.get_mut(&index) {
+                t.stop();
+            }
+        } else {
+            self.start(pty_system, dimension, index, settings);
+        }
+    }
+
+    /// Starts the member at `index` if it is not running. In a group that is not concurrent,
+    /// it becomes the displayed member.
+    pub fn start_member(
+        &mut self,
+        pty_system: &dyn PtySystem,
+        dimension: (usize, usize),
+        index: usize,
+        settings: &Settings,
+    ) {
+        if !self.process_group.concurrent && index != self.active_process_index {
+            self.set_active(pty_system, dimension, index, settings);
+        } else if !self.running_indices().contains(&index) {
+            self.start(pty_system, dimension, index, settings);
+        }
+    }
+
+    /// Starts the member at `index` again, stopping it first if it is running. In a group
+    /// that is not concurrent, it becomes the displayed member.
+    pub fn restart(
+        &mut self,
+        pty_system: &dyn PtySystem,
+        dimension: (usize, usize),
+        index: usize,
+        settings: &Settings,
+    ) {
+        if !self.process_group.concurrent && index != self.active_process_index {
+            self.set_active(pty_system, dimension, index, settings);
+        } else {
+            self.start(pty_system, dimension, index, settings);
+        }
+    }
+
+    pub fn stop_member(&mut self, index: usize) {
+        if let Some(t) = self.pty_terminals.get_mut(&index) {
+            t.stop();
+        }
+    }
+
+    pub fn start(
+        &mut self,
+        pty_system: &dyn PtySystem,
+        dimension: (usize, usize),
+        index: usize,
+        settings: &Settings,
+    ) {
+        if let Some(Process::Command {
+            label,
+            argv,
+            options,
+        }) = self.process_group.members.get(index).cloned()
+        {
+            self.restarts.remove(&index);
+            self.retire(index);
+            if let Ok(pp) = PtyProcess::new(
+                pty_system,
+                dimension,
+                &argv,
+                &self.process_group.env(index, settings),
+                options.stop_policy(settings),
+                self.process_group.log(index, settings),
+            ) {
+                let history = if options.history || settings.history {
+                    let path = settings
+                        .history_dir
+                        .join(format!("{}-{}.txt", self.process_group.title, label));
+                    History::create(&path)
+                        .map_err(|e| log::error!("history error: {}", e))
+                        .ok()
+                } else {
+                    None
+                };
+                let scroll_back = options.scroll_back.unwrap_or(settings.scroll_back);
+                let terminal = PtyTerminal::new(
+                    pp,
+                    dimension,
+                    scroll_back,
+                    history,
+                    settings.error_pattern.clone(),
+                    settings.notify.pattern.clone(),
+                );
+                self.pty_terminals.insert(index, terminal);
+            }
+        }
+    }
 
-            if let Process::Command { label: _, argv } = process {
-                if let Ok(pp) = PtyProcess::new(pty_system, dimension, argv) {
-                    self.pty_terminal = Some(PtyTerminal::new(pp, dimension));
+    /// Restarts members that exited according to their restart policy.
+    pub fn supervise(&mut self, pty_system: &dyn PtySystem, settings: &Settings) {
+        let now = Instant::now();
+        for (i, t) in self.pty_terminals.iter_mut() {
+            if let Some(Process::Command { argv, options, .. }) = self.process_group.members.get(*i)
+            {
+                if t.is_stop_requested() {
+                    self.restarts.remove(i);
+                    continue;
+                }
+                let restart = self.restarts.entry(*i).or_default();
+                match restart.scheduled_at {
+                    Some(at) if now >= at => {
+                        restart.scheduled_at = None;
+                        restart.count += 1;
+                        match PtyProcess::new(
+                            pty_system,
+                            t.dimensions(),
+                            argv,
+                            &self.process_group.env(*i, settings),
+                            options.stop_policy(settings),
+                            self.process_group.log(*i, settings),
+                        ) {
+                            Ok(pp) => t.respawn(pp),
+                            Err(e) => log::error!("restart error: {}", e),
+                        }
+                    }
+                    Some(_) => {}
+                    None => {
+                        if let Some((success, run_time)) = t.exit_summary() {
+                            let wanted = match options.restart {
+                                RestartPolicy::Never => false,
+                                RestartPolicy::OnFailure => !success,
+                                RestartPolicy::Always => true,
+                            };
+                            let (count, delay) = restart_backoff(restart.count, run_time);
+                            restart.count = count;
+                            if wanted
+                                && restart.count
+                                    < options.max_retries.unwrap_or(DEFAULT_MAX_RETRIES)
+                            {
+                                restart.scheduled_at = Some(now + delay);
+                            }
+                        }
+                    }
                 }
             }
         }
     }
 
+    /// Moves the terminal of a member out of the way, stopping its process in the background.
+    fn retire(&mut self, index: usize) {
+        if let Some(mut t) = self.pty_terminals.remove(&index) {
+            t.stop();
+            self.stopping.push(t);
+        }
+    }
+
+    pub fn stop(&mut self) {
+        self.pty_terminals.values_mut().for_each(|t| t.stop());
+    }
+
+    pub fn is_stopped(&self) -> bool {
+        self.stopping.is_empty() && self.pty_terminals.values().all(|t| t.is_stopped())
+    }
+
+    fn is_stopping(&self) -> bool {
+        !self.stopping.is_empty() || self.pty_terminals.values().any(|t| t.is_stopping())
+    }
+
     pub fn scroll_up(&mut self) {
-        if let Some(t) = &mut self.pty_terminal {
+        if let Some(t) = self.active_terminal() {
             t.scroll_up();
         }
     }
 
     pub fn scroll_down(&mut self) {
-        if let Some(t) = &mut self.pty_terminal {
+        if let Some(t) = self.active_terminal() {
             t.scroll_down();
         }
     }
 
     pub fn reset_scroll(&mut self) {
-        if let Some(t) = &mut self.pty_terminal {
+        if let Some(t) = self.active_terminal() {
             t.reset_scroll();
         }
     }
 
-    pub fn render(&mut self, screen: &mut Surface, w: usize, y: usize, h: usize, focused: bool) {
-        if let Some(t) = &mut self.pty_terminal {
-            t.resize_soft(w, h - 1);
+    pub fn set_search(&mut self, regex: Option<Regex>) {
+        if let Some(t) = self.active_terminal() {
+            t.set_search(regex);
+        }
+    }
+
+    pub fn search_step(&mut self, older: bool) {
+        if let Some(t) = self.active_terminal() {
+            t.search_step(older);
+        }
+    }
+
+    pub fn is_searching(&self) -> bool {
+        self.pty_terminals
+            .get(&self.active_process_index)
+            .map(|t| t.search.is_some())
+            .unwrap_or(false)
+    }
+
+    pub fn enter_copy_mode(&mut self) {
+        if let Some(t) = self.active_terminal() {
+            t.enter_copy_mode();
+        }
+    }
+
+    pub fn is_copy_mode(&self) -> bool {
+        self.pty_terminals
+            .get(&self.active_process_index)
+            .map(|t| t.is_copy_mode())
+            .unwrap_or(false)
+    }
+
+    pub fn copy_mode_key(&mut self, key: &KeyEvent) -> Option<Yank> {
+        self.active_terminal().and_then(|t| t.copy_mode_key(key))
+    }
+
+    pub fn send_key(&mut self, key: &KeyEvent) {
+        if let Some(t) = self.active_terminal() {
+            t.send_key(key);
+        }
+    }
+
+    pub fn send_text(&mut self, text: &str) {
+        if let Some(t) = self.active_terminal() {
+            t.send_text(text);
+        }
+    }
+
+    pub fn send_paste(&mut self, text: &str) {
+        if let Some(t) = self.active_terminal() {
+            t.send_paste(text);
         }
+    }
+
+    /// Consumes the output of every member, and forgets those stopped in the background.
+    pub fn advance(&mut self) {
+        self.pty_terminals.values_mut().for_each(|t| t.advance());
+        self.stopping.retain_mut(|t| !t.poll_stopped());
+        self.collect_activity();
+    }
 
-        let status_color = if focused {
+    fn collect_activity(&mut self) {
+        for (i, t) in self.pty_terminals.iter_mut() {
+            self.activity = self.activity.max(t.take_activity());
+            self.events
+                .extend(t.take_events().into_iter().map(|event| (*i, event)));
+        }
+    }
+
+    /// Events of members since the last call, as notifications of those `notify` asks for.
+    pub fn take_notifications(&mut self, notify: &Notify) -> Vec<Notification> {
+        let members = &self.process_group.members;
+        let title = &self.process_group.title;
+        std::mem::take(&mut self.events)
+            .into_iter()
+            .filter_map(|(i, event)| {
+                let body = match event {
+                    ProcessEvent::Exited(code) if notify.on_exit(code) => {
+                        format!("exited with {}", code)
+                    }
+                    ProcessEvent::Exited(_) => return None,
+                    ProcessEvent::Matched(line) => line,
+                };
+                let label = members.get(i).map(|it| it.label()).unwrap_or_default();
+                Some(Notification {
+                    title: format!("{}[{}]", title, label),
+                    body,
+                })
+            })
+            .collect()
+    }
+
+    pub fn activity(&self) -> Activity {
+        self.activity
+    }
+
+    /// State of the active member, with its exit code once it has exited.
+    pub fn run_state(&self) -> (RunState, Option<u32>) {
+        match self.pty_terminals.get(&self.active_process_index) {
+            None => (RunState::Stopped, None),
+            Some(t) => match t.exit_code() {
+                None => (RunState::Running, None),
+                Some(code) if t.is_stop_requested() => (RunState::Stopped, Some(code)),
+                Some(0) => (RunState::Exited, Some(0)),
+                Some(code) => (RunState::Crashed, Some(code)),
+            },
+        }
+    }
+
+    pub fn summary(&self) -> (String, AnsiColor) {
+        let (state, code) = self.run_state();
+        let code = match (state, code) {
+            (RunState::Crashed, Some(code)) => format!(" {}", code),
+            _ => String::new(),
+        };
+        let label = self.get_active().map(|it| it.label()).unwrap_or_default();
+        (
+            format!("{}[{}]: {}{}", self.process_group.title, label, state, code),
+            state.color(),
+        )
+    }
+
+    /// The group and its members, for the control socket.
+    pub fn describe(&self, focused: bool) -> Value {
+        let running = self.running_indices();
+        let members = self
+            .process_group
+            .members
+            .iter()
+            .enumerate()
+            .map(|(i, it)| json!({ "index": i, "label": it.label(), "running": running.contains(&i) }))
+            .collect::<Vec<_>>();
+        json!({
+            "group": self.process_group.title,
+            "concurrent": self.process_group.concurrent,
+            "focused": focused,
+            "active": self.active_process_index,
+            "members": members,
+        })
+    }
+
+    /// Health of the active member, for the control socket.
+    pub fn status(&self) -> Value {
+        let (state, code) = self.run_state();
+        let index = self.active_process_index;
+        let t = self.pty_terminals.get(&index);
+        json!({
+            "group": self.process_group.title,
+            "member": self.get_active().map(|it| it.label()),
+            "index": index,
+            "state": state.to_string(),
+            "pid": t.and_then(|t| t.pid()),
+            "uptime": t.map(|t| t.uptime().as_secs()),
+            "exit_code": code,
+            "last_exit_code": t.and_then(|t| t.last_exit_code()),
+            "restarts": self.restarts.get(&index).map(|it| it.count).unwrap_or(0),
+        })
+    }
+
+    /// Index of the member given by index or label in a request of the control socket.
+    fn member_index(&self, member: &Value) -> Result<usize, control::Error> {
+        let members = &self.process_group.members;
+        let index = match member {
+            Value::Number(n) => n.as_u64().map(|it| it as usize),
+            Value::String(label) => members.iter().position(|it| it.label() == *label),
+            _ => None,
+        };
+        index.filter(|i| *i < members.len()).ok_or_else(|| {
+            control::Error::invalid_params(format!(
+                "no member {} in `{}`",
+                member, self.process_group.title
+            ))
+        })
+    }
+
+    /// Health of the active member: its state, pid, uptime and exit codes.
+    fn health(&self) -> Vec<Change> {
+        let (state, code) = self.run_state();
+        let mut changes = vec![
+            Change::Text(" ".to_string()),
+            Change::Attribute(AttributeChange::Background(ColorAttribute::from(
+                state.color(),
+            ))),
+            Change::Text(match code {
+                Some(code) if state != RunState::Stopped => format!(" {} {} ", state, code),
+                _ => format!(" {} ", state),
+            }),
+        ];
+        let t = match self.pty_terminals.get(&self.active_process_index) {
+            Some(t) => t,
+            None => return changes,
+        };
+        let mut details = Vec::new();
+        if let Some(pid) = t.pid() {
+            details.push(format!("pid {}", pid));
+        }
+        let uptime = timestamp::format_duration(t.uptime());
+        details.push(match state {
+            RunState::Running => format!("up {}", uptime),
+            _ => format!("ran {}", uptime),
+        });
+        changes.push(Change::Text(format!(" {} ", details.join(" "))));
+        // While running again, the exit code of the previous run.
+        if let (RunState::Running, Some(last)) = (state, t.last_exit_code()) {
+            let color = if last == 0 {
+                AnsiColor::Green
+            } else {
+                AnsiColor::Red
+            };
+            changes.push(Change::Text(" last exit ".to_string()));
+            changes.push(Change::Attribute(AttributeChange::Background(
+                ColorAttribute::from(color),
+            )));
+            changes.push(Change::Text(format!(" {} ", last)));
+        }
+        changes
+    }
+
+    /// Draws the status line and the active member into `rect` of `screen`.
+    pub fn render(&mut self, screen: &mut Surface, rect: Rect, style: WindowStyle) {
+        let WindowStyle {
+            focused,
+            passthrough,
+            timestamps,
+        } = style;
+        if rect.width == 0 || rect.height < 2 {
+            self.advance();
+            return;
+        }
+        let (w, h) = (rect.width, rect.height);
+        let active_process_index = self.active_process_index;
+        let terminal_width = if timestamps {
+            w.saturating_sub(GUTTER_WIDTH).max(1)
+        } else {
+            w
+        };
+        self.pty_terminals.iter_mut().for_each(|(i, t)| {
+            t.resize_soft(terminal_width, h - 1);
+            // Members in the background still have to consume their output.
+            if *i != active_process_index {
+                t.advance();
+            }
+        });
+        self.stopping.retain_mut(|t| !t.poll_stopped());
+        let running = self.running_indices();
+
+        let status_color = if passthrough {
+            AnsiColor::Green
+        } else if focused {
             AnsiColor::Fuchsia
         } else {
-            AnsiColor::Grey
+            self.activity.color()
         };
         let mut changes = vec![
             Change::CursorPosition {
                 x: Position::Absolute(0),
-                y: Position::Absolute(y),
+                y: Position::Absolute(0),
             },
             Change::Attribute(AttributeChange::Background(ColorAttribute::from(
                 status_color,
@@ -367,9 +1608,14 @@ impl UiWindow {
             Change::Attribute(AttributeChange::Foreground(ColorAttribute::from(
                 AnsiColor::White,
             ))),
-            Change::Text(self.process_group.title.clone()),
-            Change::Text(" | ".to_string()),
         ];
+        if passthrough {
+            changes.push(Change::Text("[INSERT] ".to_string()));
+        } else if focused && self.is_copy_mode() {
+            changes.push(Change::Text("[COPY] ".to_string()));
+        }
+        changes.push(Change::Text(self.process_group.title.clone()));
+        changes.push(Change::Text(" | ".to_string()));
         let line = self
             .process_group
             .members
@@ -378,6 +1624,8 @@ impl UiWindow {
             .map(|(i, it)| {
                 let indicator = if i == self.active_process_index {
                     "*"
+                } else if running.contains(&i) {
+                    "+"
                 } else {
                     ""
                 };
@@ -386,6 +1634,37 @@ impl UiWindow {
             .collect::<Vec<_>>()
             .join(" ");
         changes.push(Change::Text(line));
+        changes.push(Change::Text(" |".to_string()));
+        changes.append(&mut self.health());
+        changes.push(Change::Attribute(AttributeChange::Background(
+            ColorAttribute::from(status_color),
+        )));
+        if self.is_stopping() {
+            changes.push(Change::Text(" | stopping…".to_string()));
+        }
+        if let Some(restart) = self.restarts.get(&active_process_index) {
+            match restart.scheduled_at {
+                Some(at) => changes.push(Change::Text(format!(
+                    " | restarts: {}, next in {}s",
+                    restart.count,
+                    at.saturating_duration_since(Instant::now()).as_secs() + 1
+                ))),
+                None if restart.count > 0 => {
+                    changes.push(Change::Text(format!(" | restarts: {}", restart.count)))
+                }
+                None => {}
+            }
+        }
+        if let Some((current, total)) = self
+            .pty_terminals
+            .get(&active_process_index)
+            .and_then(|t| t.search_status())
+        {
+            changes.push(Change::Text(match current {
+                Some(i) => format!(" | search: {}/{}", i + 1, total),
+                None => " | search: no match".to_string(),
+            }));
+        }
         changes.push(Change::ClearToEndOfLine(ColorAttribute::from(status_color)));
         changes.push(Change::AllAttributes(CellAttributes::default()));
         changes.push(Change::CursorPosition {
@@ -393,13 +1672,21 @@ impl UiWindow {
             y: Position::Relative(1),
         });
 
-        if let Some(pt) = &mut self.pty_terminal {
-            if let Some(mut xs) = pt.poll() {
+        if let Some(pt) = self.pty_terminals.get_mut(&active_process_index) {
+            if let Some(mut xs) = pt.poll(timestamps) {
                 changes.append(&mut xs);
             }
         }
+        self.collect_activity();
+        if focused {
+            self.activity = Activity::None;
+        }
 
-        screen.add_changes(changes);
+        // Draw on a surface of its own, so that clearing to the end of a line does not
+        // reach windows on the right.
+        let mut surface = Surface::new(w, h);
+        surface.add_changes(changes);
+        screen.draw_from_screen(&surface, rect.x, rect.y);
         screen.flush_changes_older_than(SequenceNo::MAX);
     }
 }
@@ -418,14 +1705,55 @@ impl TerminalConfiguration for TermConfig {
     }
 }
 
+/// Writer handed to the emulator, which can be pointed at the pty of a respawned process.
+#[derive(Clone)]
+struct PtyWriter(Arc<Mutex<Box<dyn Write + Send>>>);
+
+impl Write for PtyWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.lock().unwrap().flush()
+    }
+}
+
 struct PtyTerminal {
     terminal: wezterm_term::Terminal,
+    writer: PtyWriter,
     pty_process: PtyProcess,
     scroll_offset: isize,
+    search: Option<Search>,
+    copy_mode: Option<CopyMode>,
+    scroll_back: usize,
+    /// Rows that no longer fit in the scrollback, when enabled for the process.
+    history: Option<History>,
+    /// When output first arrived on each row of the scrollback.
+    timestamps: BTreeMap<StableRowIndex, SystemTime>,
+    /// Exit code of the process before the last restart.
+    last_exit_code: Option<u32>,
+    error_pattern: Option<Regex>,
+    /// Output since `take_activity` was last called.
+    activity: Activity,
+    notify_pattern: Option<Regex>,
+    /// Events since `take_events` was last called.
+    events: Vec<ProcessEvent>,
+    last_matched_at: Option<Instant>,
 }
 
 impl PtyTerminal {
-    pub fn new(pty_process: PtyProcess, dimension: (usize, usize)) -> Self {
+    pub fn new(
+        pty_process: PtyProcess,
+        dimension: (usize, usize),
+        scroll_back: usize,
+        history: Option<History>,
+        error_pattern: Option<Regex>,
+        notify_pattern: Option<Regex>,
+    ) -> Self {
+        // Hand the pty writer to the emulator so that keys are encoded according to the
+        // modes requested by the child (e.g. application cursor keys, bracketed paste).
+        let writer = PtyWriter(Arc::new(Mutex::new(pty_process.take_writer_or_sink())));
         let terminal = wezterm_term::Terminal::new(
             TerminalSize {
                 rows: dimension.1,
@@ -434,36 +1762,506 @@ impl PtyTerminal {
                 pixel_height: 0,
                 dpi: 0,
             },
-            Arc::new(TermConfig { scroll_back: 1000 }),
+            Arc::new(TermConfig { scroll_back }),
             "sudare",
             "0.1.0",
-            Box::new(Vec::new()),
+            Box::new(writer.clone()),
         );
 
         Self {
             terminal,
+            writer,
             pty_process,
             scroll_offset: 0,
+            search: None,
+            copy_mode: None,
+            scroll_back,
+            history,
+            timestamps: BTreeMap::new(),
+            last_exit_code: None,
+            error_pattern,
+            activity: Activity::None,
+            notify_pattern,
+            events: Vec::new(),
+            last_matched_at: None,
+        }
+    }
+
+    pub fn scroll_up(&mut self) {
+        if self.scroll_offset > -self.max_scroll() {
+            self.scroll_offset -= 1;
+        }
+    }
+
+    pub fn scroll_down(&mut self) {
+        if self.scroll_offset < 0 {
+            self.scroll_offset += 1;
+        }
+    }
+
+    pub fn reset_scroll(&mut self) {
+        self.scroll_offset = 0;
+    }
+
+    /// Starts searching for `regex`, or clears the search with `None`, and jumps to the
+    /// closest match above the bottom of the view.
+    pub fn set_search(&mut self, regex: Option<Regex>) {
+        self.search = regex.map(Search::new);
+        self.update_search();
+        let bottom = self.view_top() + self.terminal.screen().physical_rows as isize - 1;
+        if let Some(search) = self.search.as_mut() {
+            search.select_before(bottom);
+        }
+        self.scroll_to_match();
+    }
+
+    /// Jumps to the previous (older) or next match, if there is a search.
+    pub fn search_step(&mut self, older: bool) {
+        if self.search.is_none() {
+            return;
+        }
+        self.update_search();
+        if let Some(search) = self.search.as_mut() {
+            search.step(older);
+        }
+        self.scroll_to_match();
+    }
+
+    /// Position of the current match and number of matches.
+    pub fn search_status(&self) -> Option<(Option<usize>, usize)> {
+        self.search
+            .as_ref()
+            .map(|it| (it.current, it.matches.len()))
+    }
+
+    /// Finds the matches in the rows that changed since the last call.
+    fn update_search(&mut self) {
+        let (regex, scanned) = match &self.search {
+            Some(search) => (search.regex.clone(), search.scanned),
+            None => return,
+        };
+        let first = self.first_row();
+        let end = first + self.total_rows() as isize;
+        let from = scanned.unwrap_or(first).max(first);
+        // Go through the history a chunk at a time rather than loading it all.
+        let mut matches = Vec::new();
+        let mut start = from;
+        while start < end {
+            let rows = start..(start + SEARCH_CHUNK_ROWS).min(end);
+            self.lines(rows.clone())
+                .iter()
+                .zip(rows.clone())
+                .for_each(|(line, row)| {
+                    matches.extend(
+                        search::line_matches(&regex, line)
+                            .into_iter()
+                            .map(|cells| search::SearchMatch { row, cells }),
+                    )
+                });
+            start = rows.end;
+        }
+        // Rows that scrolled off the screen no longer change.
+        let screen_top = self.terminal.screen().phys_to_stable_row_index(0);
+        if let Some(search) = self.search.as_mut() {
+            search.update(first..from, matches, screen_top);
+        }
+    }
+
+    /// Scrolls so that the current match is in the middle of the view.
+    fn scroll_to_match(&mut self) {
+        if let Some(row) = self
+            .search
+            .as_ref()
+            .and_then(|it| it.current_match())
+            .map(|it| it.row)
+        {
+            self.scroll_into_view(row, true);
+        }
+    }
+
+    /// Scrolls as little as possible to show `row`, or puts it in the middle with `center`.
+    fn scroll_into_view(&mut self, row: StableRowIndex, center: bool) {
+        let rows = self.terminal.screen().physical_rows as isize;
+        let top = self.max_scroll();
+        let first_visible = self.view_top();
+        let offset = if center {
+            row - rows / 2 - self.first_row() - top
+        } else if row < first_visible {
+            row - self.first_row() - top
+        } else if row >= first_visible + rows {
+            row - rows + 1 - self.first_row() - top
+        } else {
+            self.scroll_offset
+        };
+        self.scroll_offset = offset.clamp(-top, 0);
+    }
+
+    /// Number of rows only found in the history file.
+    fn spilled_rows(&self) -> usize {
+        match &self.history {
+            Some(history) if !self.terminal.is_alt_screen_active() => {
+                let first_retained = self.terminal.screen().phys_to_stable_row_index(0);
+                history.len().min(first_retained.max(0) as usize)
+            }
+            _ => 0,
+        }
+    }
+
+    /// Oldest row that can be scrolled to.
+    fn first_row(&self) -> StableRowIndex {
+        self.terminal.screen().phys_to_stable_row_index(0) - self.spilled_rows() as isize
+    }
+
+    /// Number of rows from `first_row` to the bottom of the screen.
+    fn total_rows(&self) -> usize {
+        self.terminal.screen().scrollback_rows() + self.spilled_rows()
+    }
+
+    fn max_scroll(&self) -> isize {
+        (self.total_rows() - self.terminal.screen().physical_rows) as isize
+    }
+
+    /// Row at the top of the view.
+    fn view_top(&self) -> StableRowIndex {
+        self.first_row() + self.max_scroll() + self.scroll_offset
+    }
+
+    /// Rows of the screen, the scrollback or the history. Rows that can't be read are blank.
+    fn lines(&self, rows: Range<StableRowIndex>) -> Vec<Line> {
+        let screen = self.terminal.screen();
+        let first_retained = screen.phys_to_stable_row_index(0);
+        let spilled = rows.start..rows.end.min(first_retained);
+        let mut lines = match (&self.history, spilled.is_empty()) {
+            (Some(history), false) => history
+                .rows(spilled.start as usize..spilled.end as usize)
+                .unwrap_or_else(|e| {
+                    log::error!("history error: {}", e);
+                    Vec::new()
+                }),
+            _ => Vec::new(),
+        }
+        .iter()
+        .map(|(_, text)| Line::from(text.as_str()))
+        .collect::<Vec<_>>();
+        lines.resize_with(spilled.len(), || Line::from(""));
+
+        let retained = rows.start.max(first_retained)..rows.end;
+        if !retained.is_empty() {
+            lines.extend(screen.lines_in_phys_range(screen.stable_range(&retained)));
+        }
+        lines
+    }
+
+    /// Text of the last `n` rows printed, up to the cursor.
+    fn tail(&self, n: usize) -> Vec<String> {
+        let end = self.cursor_row() + 1;
+        let start = end
+            .saturating_sub(n.min(isize::MAX as usize) as isize)
+            .max(self.first_row());
+        let mut lines = self
+            .lines(start..end)
+            .iter()
+            .map(|line| line.as_str().trim_end().to_string())
+            .collect::<Vec<_>>();
+        if lines.last().map(|it| it.is_empty()).unwrap_or(false) {
+            lines.pop();
+        }
+        lines
+    }
+
+    fn line(&self, row: StableRowIndex) -> Option<Line> {
+        self.lines(row..row + 1).pop()
+    }
+
+    /// When output arrived on each of `rows`, if known.
+    fn row_times(&self, rows: Range<StableRowIndex>) -> Vec<Option<SystemTime>> {
+        let first_retained = self.terminal.screen().phys_to_stable_row_index(0);
+        let spilled = rows.start..rows.end.min(first_retained);
+        let mut times = match (&self.history, spilled.is_empty()) {
+            (Some(history), false) => history
+                .rows(spilled.start as usize..spilled.end as usize)
+                .map(|rows| rows.into_iter().map(|(time, _)| time).collect())
+                .unwrap_or_default(),
+            _ => Vec::new(),
+        };
+        times.resize(spilled.len(), None);
+        times.extend(
+            (rows.start.max(first_retained)..rows.end)
+                .map(|row| self.timestamps.get(&row).copied()),
+        );
+        times
+    }
+
+    /// Row of the cursor of the process.
+    fn cursor_row(&self) -> StableRowIndex {
+        let pos = self.terminal.cursor_pos();
+        self.terminal.screen().visible_row_to_stable_row(pos.y)
+    }
+
+    /// Records `time` for the rows output was written to since the cursor was at `from`.
+    fn record_timestamps(&mut self, from: StableRowIndex, time: SystemTime) {
+        if self.terminal.is_alt_screen_active() {
+            return;
+        }
+        let to = self.cursor_row();
+        // The row of the cursor only counts once something is printed on it.
+        let end = if self.terminal.cursor_pos().x > 0 {
+            to + 1
+        } else {
+            to
+        };
+        for row in from..end {
+            self.timestamps.entry(row).or_insert(time);
+        }
+    }
+
+    /// Looks for errors and lines to notify in the rows output was written to since the
+    /// cursor was at `from`.
+    fn scan_output(&mut self, from: StableRowIndex) {
+        let error_pattern = self
+            .error_pattern
+            .as_ref()
+            .filter(|_| self.activity < Activity::Error);
+        let notify_pattern = self.notify_pattern.as_ref().filter(|_| {
+            self.last_matched_at
+                .map(|at| at.elapsed() >= MATCH_NOTIFY_INTERVAL)
+                .unwrap_or(true)
+        });
+        if error_pattern.is_none() && notify_pattern.is_none() {
+            return;
+        }
+
+        let screen = self.terminal.screen();
+        let rows = from..self.cursor_row() + 1;
+        let lines = screen
+            .lines_in_phys_range(screen.stable_range(&rows))
+            .iter()
+            .map(|line| line.as_str().trim_end().to_string())
+            .collect::<Vec<_>>();
+        if let Some(regex) = error_pattern {
+            if lines.iter().any(|line| regex.is_match(line)) {
+                self.activity = Activity::Error;
+            }
+        }
+        if let Some(regex) = notify_pattern {
+            if let Some(line) = lines.into_iter().find(|line| regex.is_match(line)) {
+                self.events.push(ProcessEvent::Matched(line));
+                self.last_matched_at = Some(Instant::now());
+            }
+        }
+    }
+
+    pub fn take_activity(&mut self) -> Activity {
+        std::mem::take(&mut self.activity)
+    }
+
+    pub fn take_events(&mut self) -> Vec<ProcessEvent> {
+        std::mem::take(&mut self.events)
+    }
+
+    /// Saves the rows that scrolled off the screen to the history.
+    fn save_history(&mut self) {
+        if self.terminal.is_alt_screen_active() {
+            return;
+        }
+        let history = match self.history.as_mut() {
+            Some(history) => history,
+            None => return,
+        };
+        let screen = self.terminal.screen();
+        let first_retained = screen.phys_to_stable_row_index(0);
+        let first_visible =
+            screen.phys_to_stable_row_index(screen.scrollback_rows() - screen.physical_rows);
+        let mut result = Ok(());
+        // Rows dropped before they could be saved are left blank to keep the numbering.
+        while result.is_ok() && (history.len() as isize) < first_retained {
+            result = history.push("", None);
+        }
+        let unsaved = history.len() as isize..first_visible;
+        if result.is_ok() && !unsaved.is_empty() {
+            let timestamps = &self.timestamps;
+            result = screen
+                .lines_in_phys_range(screen.stable_range(&unsaved))
+                .iter()
+                .zip(unsaved)
+                .try_for_each(|(line, row)| {
+                    history.push(line.as_str().trim_end(), timestamps.get(&row).copied())
+                });
+        }
+        if let Err(e) = result {
+            log::error!("history error: {}", e);
+            self.history = None;
+        }
+    }
+
+    /// Puts a cursor at the cursor of the process, or at the bottom of the view when
+    /// scrolled back.
+    pub fn enter_copy_mode(&mut self) {
+        let screen = self.terminal.screen();
+        let cursor = if self.scroll_offset == 0 {
+            let pos = self.terminal.cursor_pos();
+            (screen.visible_row_to_stable_row(pos.y), pos.x)
+        } else {
+            (self.view_top() + screen.physical_rows as isize - 1, 0)
+        };
+        self.copy_mode = Some(CopyMode::new(cursor));
+    }
+
+    pub fn is_copy_mode(&self) -> bool {
+        self.copy_mode.is_some()
+    }
+
+    /// Moves the cursor or changes the selection with vi-like keys. Returns the selected
+    /// text when it is yanked, which also leaves copy mode.
+    pub fn copy_mode_key(&mut self, key: &KeyEvent) -> Option<Yank> {
+        let mut copy_mode = self.copy_mode.take()?;
+        let first_row = self.first_row();
+        let last_row = first_row + self.total_rows() as isize - 1;
+        let rows = self.terminal.screen().physical_rows as isize;
+        let cols = self.terminal.get_size().cols;
+        let (row, col) = copy_mode.cursor;
+
+        let cursor = match (key.key, key.modifiers) {
+            (KeyCode::Escape, _) | (KeyCode::Char('q'), _) => return None,
+            (KeyCode::Enter, _) | (KeyCode::Char('y'), _) => {
+                return Some(Yank::Clipboard(copy_mode.text(|row| self.line(row))));
+            }
+            (KeyCode::Char('Y'), _) => {
+                return Some(Yank::File(copy_mode.text(|row| self.line(row))))
+            }
+            (KeyCode::Char('v'), _) => {
+                copy_mode.toggle_selection(SelectionKind::Rectangle);
+                (row, col)
+            }
+            (KeyCode::Char('V'), _) => {
+                copy_mode.toggle_selection(SelectionKind::Line);
+                (row, col)
+            }
+            (KeyCode::Char('u'), Modifiers::CTRL) | (KeyCode::PageUp, _) => (row - rows / 2, col),
+            (KeyCode::Char('d'), Modifiers::CTRL) | (KeyCode::PageDown, _) => (row + rows / 2, col),
+            (KeyCode::Char('h'), _) | (KeyCode::LeftArrow, _) => (row, col.saturating_sub(1)),
+            (KeyCode::Char('l'), _) | (KeyCode::RightArrow, _) => (row, col + 1),
+            (KeyCode::Char('k'), _) | (KeyCode::UpArrow, _) => (row - 1, col),
+            (KeyCode::Char('j'), _) | (KeyCode::DownArrow, _) => (row + 1, col),
+            (KeyCode::Char('0'), _) | (KeyCode::Home, _) => (row, 0),
+            (KeyCode::Char('$'), _) | (KeyCode::End, _) => {
+                let end = self.line(row).and_then(|line| {
+                    line.visible_cells()
+                        .filter(|cell| !cell.str().trim().is_empty())
+                        .last()
+                        .map(|cell| cell.cell_index())
+                });
+                (row, end.unwrap_or(0))
+            }
+            (KeyCode::Char('g'), _) => (first_row, col),
+            (KeyCode::Char('G'), _) => (last_row, col),
+            _ => (row, col),
+        };
+
+        copy_mode.cursor = (
+            cursor.0.clamp(first_row, last_row),
+            cursor.1.min(cols.saturating_sub(1)),
+        );
+        let row = copy_mode.cursor.0;
+        self.copy_mode = Some(copy_mode);
+        self.scroll_into_view(row, false);
+        None
+    }
+
+    pub fn stop(&mut self) {
+        self.pty_process.stop();
+    }
+
+    /// Replaces the exited process with a new one, keeping the output of the previous run.
+    pub fn respawn(&mut self, pty_process: PtyProcess) {
+        self.last_exit_code = self.exit_code();
+        *self.writer.0.lock().unwrap() = pty_process.take_writer_or_sink();
+        self.pty_process = pty_process;
+        self.terminal.advance_bytes(b"\r\n");
+    }
+
+    pub fn dimensions(&self) -> (usize, usize) {
+        let c = self.terminal.get_size();
+        (c.cols, c.rows)
+    }
+
+    pub fn is_stop_requested(&self) -> bool {
+        self.pty_process.stop_requested_at.is_some()
+    }
+
+    /// Whether the process exited successfully and how long it ran, once it has exited.
+    pub fn exit_summary(&self) -> Option<(bool, Duration)> {
+        self.pty_process
+            .exit_status
+            .as_ref()
+            .map(|status| (status.success(), self.uptime()))
+    }
+
+    pub fn last_exit_code(&self) -> Option<u32> {
+        self.last_exit_code
+    }
+
+    /// Pid of the process while it is running.
+    pub fn pid(&self) -> Option<u32> {
+        match self.pty_process.exit_status {
+            Some(_) => None,
+            None => self.pty_process.child.process_id(),
         }
     }
 
-    pub fn scroll_up(&mut self) {
-        if self.scroll_offset
-            > -((self.terminal.screen().scrollback_rows() - self.terminal.screen().physical_rows)
-                as isize)
-        {
-            self.scroll_offset -= 1;
+    /// How long the process has been running, or ran for.
+    pub fn uptime(&self) -> Duration {
+        self.pty_process
+            .exited_at
+            .unwrap_or_else(Instant::now)
+            .duration_since(self.pty_process.started_at)
+    }
+
+    pub fn exit_code(&self) -> Option<u32> {
+        self.pty_process
+            .exit_status
+            .as_ref()
+            .map(|it| it.exit_code())
+    }
+
+    pub fn is_stopping(&self) -> bool {
+        self.pty_process.is_stopping()
+    }
+
+    pub fn is_stopped(&self) -> bool {
+        self.pty_process.is_stopped()
+    }
+
+    /// Drives a terminal that is no longer displayed, returning true once its process is gone.
+    pub fn poll_stopped(&mut self) -> bool {
+        self.pty_process.poll();
+        self.pty_process.is_stopped()
+    }
+
+    pub fn send_key(&mut self, key: &KeyEvent) {
+        self.reset_scroll();
+        if let Err(e) = self.terminal.key_down(key.key, key.modifiers) {
+            log::error!("key_down error: {}", e);
         }
     }
 
-    pub fn scroll_down(&mut self) {
-        if self.scroll_offset < 0 {
-            self.scroll_offset += 1;
+    /// Writes `text` to the process as if it was typed.
+    pub fn send_text(&mut self, text: &str) {
+        self.reset_scroll();
+        if let Err(e) = self
+            .writer
+            .write_all(text.as_bytes())
+            .and_then(|_| self.writer.flush())
+        {
+            log::error!("send_text error: {}", e);
         }
     }
 
-    pub fn reset_scroll(&mut self) {
-        self.scroll_offset = 0;
+    pub fn send_paste(&mut self, text: &str) {
+        self.reset_scroll();
+        if let Err(e) = self.terminal.send_paste(text) {
+            log::error!("send_paste error: {}", e);
+        }
     }
 
     pub fn resize_soft(&mut self, w: usize, h: usize) {
@@ -475,53 +2273,124 @@ impl PtyTerminal {
                 pixel_width: 0,
                 pixel_height: 0,
                 dpi: 0,
-            })
+            });
+            // Let the child know as well, so that it receives SIGWINCH and redraws.
+            if let Err(e) = self.pty_process.resize((w, h)) {
+                log::error!("resize error: {}", e);
+            }
+            // Rows are wrapped again, so the matches have to be found again.
+            if let Some(search) = self.search.as_mut() {
+                search.scanned = None;
+            }
         }
     }
 
-    pub fn poll(&mut self) -> Option<Vec<Change>> {
+    /// Feeds pending output of the process into the emulator.
+    pub fn advance(&mut self) {
+        let was_running = self.pty_process.exit_status.is_none();
         let buffer = self.pty_process.poll();
-        if !buffer.is_empty() {
-            self.terminal.advance_bytes(&buffer);
+        if let (true, Some(code)) = (was_running, self.exit_code()) {
+            if !self.is_stop_requested() {
+                self.events.push(ProcessEvent::Exited(code));
+            }
+        }
+        if buffer.is_empty() {
+            return;
+        }
+        self.activity = self.activity.max(Activity::Output);
+        let now = SystemTime::now();
+        // Feed a limited number of lines at a time, so that rows are saved to the history
+        // and timestamped before they fall off the scrollback.
+        let max_lines = (self.scroll_back / 2).max(1);
+        let mut rest = buffer.as_slice();
+        while !rest.is_empty() {
+            let end = rest
+                .iter()
+                .enumerate()
+                .filter(|(_, b)| **b == b'\n')
+                .nth(max_lines - 1)
+                .map(|(i, _)| i + 1)
+                .unwrap_or(rest.len());
+            let from = self.cursor_row();
+            self.terminal.advance_bytes(&rest[..end]);
+            self.record_timestamps(from, now);
+            self.scan_output(from);
+            self.save_history();
+            rest = &rest[end..];
+        }
+
+        let first_retained = self.terminal.screen().phys_to_stable_row_index(0);
+        if matches!(self.timestamps.keys().next(), Some(row) if *row < first_retained) {
+            self.timestamps = self.timestamps.split_off(&first_retained);
         }
+    }
+
+    /// Renders the visible rows, preceded by the time they were printed with `timestamps`.
+    pub fn poll(&mut self, timestamps: bool) -> Option<Vec<Change>> {
+        self.advance();
 
         let c = self.terminal.get_size();
-        let visible_range = Range {
-            start: self.scroll_offset as ScrollbackOrVisibleRowIndex,
-            end: (self.scroll_offset + c.rows as isize) as ScrollbackOrVisibleRowIndex,
-        };
+        let top = self.view_top();
+        let visible_range = top..top + c.rows as StableRowIndex;
 
-        let screen = self.terminal.screen();
-        //screen.physical_rows
-        let (_, changes) = screen
-            .lines_in_phys_range(screen.scrollback_or_visible_range(&visible_range))
-            .iter()
-            .fold(
-                (CellAttributes::default(), Vec::<Change>::new()),
-                |(a, mut xs), line| {
-                    line.visible_cells()
-                        .last()
-                        .map(|c| {
-                            //let ys = &mut xs;
-                            xs.extend(line.changes(&a));
-                            xs.push(Change::ClearToEndOfLine(ColorAttribute::Default));
-                            xs.push(Change::CursorPosition {
-                                x: Position::Absolute(0),
-                                y: Position::Relative(1),
-                            });
-                            // TODO: c.attrs().wrapped() ?
-                            (c.attrs().clone(), xs.to_vec())
-                        })
-                        .unwrap_or({
-                            xs.push(Change::ClearToEndOfLine(ColorAttribute::Default));
-                            xs.push(Change::CursorPosition {
-                                x: Position::Absolute(0),
-                                y: Position::Relative(1),
-                            });
-                            (a, xs)
-                        })
-                },
-            );
+        let gutters = if timestamps {
+            self.row_times(visible_range.clone())
+                .into_iter()
+                .map(|time| {
+                    let text = time
+                        .map(|it| timestamp::format_local(it, true))
+                        .unwrap_or_default();
+                    Some(format!("{:<w$}", text, w = GUTTER_WIDTH))
+                })
+                .collect()
+        } else {
+            vec![None; c.rows]
+        };
+        let mut gutter_attrs = CellAttributes::default();
+        gutter_attrs
+            .set_intensity(Intensity::Half)
+            .set_foreground(ColorAttribute::from(AnsiColor::Grey));
+
+        let mut lines = self.lines(visible_range.clone());
+        lines.iter_mut().zip(visible_range).for_each(|(line, row)| {
+            if let Some(search) = &self.search {
+                search.highlight(line, row);
+            }
+            if let Some(copy_mode) = &self.copy_mode {
+                copy_mode.highlight(line, row);
+            }
+        });
+        let (_, changes) = lines.iter().zip(gutters.iter()).fold(
+            (CellAttributes::default(), Vec::<Change>::new()),
+            |(a, mut xs), (line, gutter)| {
+                if let Some(gutter) = gutter {
+                    xs.push(Change::AllAttributes(gutter_attrs.clone()));
+                    xs.push(Change::Text(gutter.clone()));
+                    xs.push(Change::AllAttributes(a.clone()));
+                }
+                line.visible_cells()
+                    .last()
+                    .map(|c| {
+                        //let ys = &mut xs;
+                        xs.extend(line.changes(&a));
+                        xs.push(Change::ClearToEndOfLine(ColorAttribute::Default));
+                        xs.push(Change::CursorPosition {
+                            x: Position::Absolute(0),
+                            y: Position::Relative(1),
+                        });
+                        // TODO: c.attrs().wrapped() ?
+                        (c.attrs().clone(), xs.to_vec())
+                    })
+                    .unwrap_or({
+                        xs.push(Change::ClearToEndOfLine(ColorAttribute::Default));
+                        xs.push(Change::CursorPosition {
+                            x: Position::Absolute(0),
+                            y: Position::Relative(1),
+                        });
+                        (a, xs)
+                    })
+            },
+        );
         Some(changes)
     }
 }
@@ -530,12 +2399,31 @@ enum PtyMessage {
     Bytes(Vec<u8>),
 }
 
+/// Width of the timestamps shown before each row, including a space.
+const GUTTER_WIDTH: usize = 13;
+
+/// How long to wait for the output of a process after it exits.
+const EXIT_OUTPUT_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// How a process is asked to stop before it gets killed.
+#[derive(Debug, Clone, Copy)]
+struct StopPolicy {
+    signal: libc::c_int,
+    timeout: Duration,
+}
+
 struct PtyProcess {
-    pty: PtyPair,
+    master: Box<dyn MasterPty + Send>,
     child: Box<dyn Child + Send + Sync>,
-    child_handle: Option<JoinHandle<()>>,
     receiver: Receiver<PtyMessage>,
     exit_status: Option<ExitStatus>,
+    started_at: Instant,
+    exited_at: Option<Instant>,
+    stop_policy: StopPolicy,
+    stop_requested_at: Option<Instant>,
+    killed: bool,
+    /// Files the output is copied to, when logging is enabled.
+    log: Option<ProcessLog>,
 }
 
 impl PtyProcess {
@@ -543,8 +2431,11 @@ impl PtyProcess {
         pty_system: &dyn PtySystem,
         dimension: (usize, usize),
         argv: &str,
+        env: &dotenv::Env,
+        stop_policy: StopPolicy,
+        log: Option<ProcessLog>,
     ) -> Result<Self, Error> {
-        let pty = pty_system.openpty(PtySize {
+        let PtyPair { master, slave } = pty_system.openpty(PtySize {
             rows: dimension.1 as u16,
             cols: dimension.0 as u16,
             pixel_width: 0,
@@ -555,58 +2446,132 @@ impl PtyProcess {
         cmd.args(["-c", argv]);
         let current_dir = std::env::current_dir()?;
         cmd.cwd(current_dir.as_os_str());
-        let maybe_child = pty.slave.spawn_command(cmd);
-        drop(&pty.slave);
+        env.iter().for_each(|(k, v)| cmd.env(k, v));
+        let maybe_child = slave.spawn_command(cmd);
+        // Only the child may keep the slave open, so that reads fail once it has exited and
+        // all of its output has been read.
+        drop(slave);
         let child = maybe_child?;
 
         let (tx, receiver) = mpsc::channel();
-        let mut reader = pty.master.try_clone_reader()?;
+        let mut reader = master.try_clone_reader()?;
 
-        let child_handle = thread::Builder::new()
+        // The thread is not joined: it ends by itself once the pty is closed, which a grandchild
+        // holding it open may delay long after the process is dropped.
+        thread::Builder::new()
             .name(argv.to_string())
             .spawn(move || {
                 let mut buffer = [0u8; 1024];
                 loop {
-                    let n = reader.read(&mut buffer[..]).unwrap();
-                    if n == 0 {
-                        break;
-                    } else {
-                        tx.send(PtyMessage::Bytes(buffer[..n].to_vec())).unwrap();
+                    // The read fails with EIO once the child side of the pty is closed, and the
+                    // send fails once the process has been dropped.
+                    match reader.read(&mut buffer[..]) {
+                        Ok(0) | Err(_) => break,
+                        Ok(n) => {
+                            if tx.send(PtyMessage::Bytes(buffer[..n].to_vec())).is_err() {
+                                break;
+                            }
+                        }
                     }
                 }
                 log::info!("thread finished");
             })?;
 
         Ok(Self {
-            pty,
+            master,
             child,
-            child_handle: Some(child_handle),
             receiver,
             exit_status: None,
+            started_at: Instant::now(),
+            exited_at: None,
+            stop_policy,
+            stop_requested_at: None,
+            killed: false,
+            log,
         })
     }
 
+    pub fn resize(&self, dimension: (usize, usize)) -> Result<(), Error> {
+        self.master.resize(PtySize {
+            rows: dimension.1 as u16,
+            cols: dimension.0 as u16,
+            pixel_width: 0,
+            pixel_height: 0,
+        })?;
+        Ok(())
+    }
+
+    pub fn take_writer(&self) -> Result<Box<dyn Write + Send>, Error> {
+        Ok(self.master.take_writer()?)
+    }
+
+    fn take_writer_or_sink(&self) -> Box<dyn Write + Send> {
+        match self.take_writer() {
+            Ok(w) => w,
+            Err(e) => {
+                log::error!("take_writer error: {}", e);
+                Box::new(io::sink())
+            }
+        }
+    }
+
+    /// Sends a signal to the whole process group, so that grandchildren spawned through
+    /// `sh -c` are reached too. The child is a session leader, so its pid is the group id.
+    fn signal_group(&self, signal: libc::c_int) -> std::io::Result<()> {
+        match self.child.process_id() {
+            Some(pid) => {
+                if unsafe { libc::killpg(pid as libc::pid_t, signal) } == 0 {
+                    Ok(())
+                } else {
+                    Err(io::Error::last_os_error())
+                }
+            }
+            None => Ok(()),
+        }
+    }
+
+    fn is_group_alive(&self) -> bool {
+        self.signal_group(0).is_ok()
+    }
+
+    /// Starts the graceful shutdown sequence without waiting for it to finish.
+    /// `poll` escalates to SIGKILL once the timeout has elapsed.
+    pub fn stop(&mut self) {
+        if self.stop_requested_at.is_some() {
+            return;
+        }
+        self.stop_requested_at = Some(Instant::now());
+        if self.is_group_alive() {
+            if let Err(e) = self.signal_group(self.stop_policy.signal) {
+                log::error!("stop error: {}", e);
+            }
+        }
+    }
+
+    pub fn is_stopping(&self) -> bool {
+        self.stop_requested_at.is_some() && !self.is_stopped()
+    }
+
+    pub fn is_stopped(&self) -> bool {
+        self.exit_status.is_some() && !self.is_group_alive()
+    }
+
+    pub fn exit_status(&self) -> Option<&ExitStatus> {
+        self.exit_status.as_ref()
+    }
+
     pub fn kill(&mut self) -> std::io::Result<()> {
-        match self.child.try_wait() {
-            Ok(Some(_)) => Ok(()),
-            Ok(None) => self.child.kill(),
-            Err(e) => Err(e),
-        }
-        // if let Some(handle) = self.child_handle.take() {
-        //     let r = match handle.join() {
-        //         Ok(_) => Ok(()),
-        //         Err(e) => Err(std::io::Error::new(ErrorKind::Other, format!("{:?}", e))),
-        //     };
-        //     self.child_handle = None;
-        //     r
-        // } else {
-        //     Ok(())
-        // }
+        if self.is_group_alive() {
+            self.signal_group(libc::SIGKILL)
+        } else {
+            Ok(())
+        }
     }
 
     pub fn poll(&mut self) -> Vec<u8> {
         let mut buffer = Vec::<u8>::new();
 
+        let mut drained = false;
         loop {
             match self.receiver.try_recv() {
                 Ok(PtyMessage::Bytes(mut bytes)) => {
@@ -616,13 +2581,20 @@ impl PtyProcess {
                     }
                 }
                 Err(TryRecvError::Empty) => break,
-                Err(TryRecvError::Disconnected) => break,
+                Err(TryRecvError::Disconnected) => {
+                    drained = true;
+                    break;
+                }
             }
         }
 
         match self.child.try_wait() {
             Ok(Some(r)) => {
-                if self.exit_status.is_none() {
+                let exited_at = *self.exited_at.get_or_insert_with(Instant::now);
+                // Wait for the output still in the pty, unless something else holds it open.
+                if self.exit_status.is_none()
+                    && (drained || exited_at.elapsed() >= EXIT_OUTPUT_TIMEOUT)
+                {
                     buffer.append(
                         &mut format!("[process exited with {}]", r.exit_code())
                             .as_bytes()
@@ -637,6 +2609,22 @@ impl PtyProcess {
             }
         }
 
+        if let Some(process_log) = self.log.as_mut() {
+            if let Err(e) = process_log.write(&buffer) {
+                log::error!("log error: {}", e);
+                self.log = None;
+            }
+        }
+
+        if let Some(at) = self.stop_requested_at {
+            if !self.killed && at.elapsed() >= self.stop_policy.timeout {
+                self.killed = true;
+                if let Err(e) = self.kill() {
+                    log::error!("kill error: {}", e);
+                }
+            }
+        }
+
         buffer
     }
 }
@@ -645,19 +2633,20 @@ impl Drop for PtyProcess {
     fn drop(&mut self) {
         log::debug!("pty_process dropped");
 
-        let writer = self.pty.master.take_writer().unwrap();
-        drop(writer);
-
-        self.kill().unwrap();
-
-        self.child.wait().unwrap();
+        // The writer is normally owned by the emulator; only close it here if nobody took it.
+        if let Ok(writer) = self.master.take_writer() {
+            drop(writer);
+        }
 
-        drop(&self.pty.master);
+        // Whatever is still running has not been stopped gracefully; SIGKILL makes the wait
+        // below return immediately.
+        if let Err(e) = self.kill() {
+            log::error!("kill error: {}", e);
+        }
 
-        drop(&self.child_handle);
-        // if let Some(handle) = self.child_handle.take() {
-        //     handle.join().unwrap();
-        // }
+        if let Err(e) = self.child.wait() {
+            log::error!("wait error: {}", e);
+        }
         log::debug!("done");
     }
 }
@@ -665,6 +2654,257 @@ impl Drop for PtyProcess {
 use sha2::Digest;
 use std::os::unix::ffi::OsStrExt;
 
+const USAGE: &str = "\
+Usage: sudare [OPTIONS] <procfile>
+       sudare server [OPTIONS] <procfile>
+       sudare check [OPTIONS] <procfile>
+       sudare run [--abort-on-failure] [OPTIONS] <procfile>
+       sudare ctl [-f|--procfile FILE] <command> [ARGS]  (see `sudare ctl --help`)
+
+Options:
+  -t, --timeout SECONDS  Seconds to wait for processes to stop before killing them
+  -e, --env FILE         Load environment variables from FILE (repeatable)
+  -p, --port PORT        Base port for the PORT variable
+  --layout LAYOUT        stack (default), horizontal, grid or tabs
+  --error-pattern REGEX  Output flagging a group as having errors
+                         (default: ERROR|panic|Traceback, empty to disable)
+  --notify TRIGGER       Notify when a process exits (`exit`), exits with a
+                         non-zero code (`failure`) or prints a line matching
+                         a regular expression (`match=REGEX`); repeatable
+  --notify-command CMD   Also run CMD with SUDARE_TITLE and SUDARE_BODY set
+  --scrollback LINES     Lines kept in memory for scrolling back (default: 1000)
+  --history              Save lines beyond the scrollback to files, to scroll and search them
+  --log-dir DIR          Save the output of every process to DIR/<group>-<label>.log
+  --log-size MB          Rotate log files larger than MB megabytes (default: 10)
+  --log-keep N           Number of rotated log files to keep (default: 5)
+  --abort-on-failure     Stop everything when a process fails (run only)";
+
+enum Command {
+    /// Attach to the session of the Procfile, starting it in the background if needed.
+    Start,
+    /// Run the session that clients attach to, in the foreground.
+    Server,
+    /// Validate the Procfile and print its groups.
+    Check,
+    /// Run the default members with their output prefixed, like `foreman start`.
+    Run { abort_on_failure: bool },
+}
+
+fn parse_args(args: &[String]) -> Result<(Command, PathBuf, Settings), String> {
+    let mut settings = Settings::default();
+    let mut procfile_path = None;
+    let mut it = args.iter().skip(1).peekable();
+    let mut command = match it.peek().map(|it| it.as_str()) {
+        Some("check") => {
+            it.next();
+            Command::Check
+        }
+        Some("run") => {
+            it.next();
+            Command::Run {
+                abort_on_failure: false,
+            }
+        }
+        Some("server") => {
+            it.next();
+            Command::Server
+        }
+        _ => Command::Start,
+    };
+    while let Some(arg) = it.next() {
+        match arg.as_str() {
+            "--abort-on-failure" => match &mut command {
+                Command::Run { abort_on_failure } => *abort_on_failure = true,
+                _ => return Err(format!("{} is only available for run", arg)),
+            },
+            "-t" | "--timeout" => {
+                let value = it
+                    .next()
+                    .ok_or_else(|| format!("{} requires a value", arg))?;
+                settings.stop_timeout = value
+                    .parse::<u64>()
+                    .map(Duration::from_secs)
+                    .map_err(|_| format!("invalid timeout `{}`", value))?;
+            }
+            "--layout" => {
+                let value = it
+                    .next()
+                    .ok_or_else(|| format!("{} requires a value", arg))?;
+                settings.layout = value.parse()?;
+            }
+            "--error-pattern" => {
+                let value = it
+                    .next()
+                    .ok_or_else(|| format!("{} requires a value", arg))?;
+                settings.error_pattern = if value.is_empty() {
+                    None
+                } else {
+                    Some(
+                        Regex::new(value)
+                            .map_err(|e| format!("invalid error pattern `{}`: {}", value, e))?,
+                    )
+                };
+            }
+            "--notify" => {
+                let value = it
+                    .next()
+                    .ok_or_else(|| format!("{} requires a value", arg))?;
+                settings.notify.add_trigger(value)?;
+            }
+            "--notify-command" => {
+                let value = it
+                    .next()
+                    .ok_or_else(|| format!("{} requires a value", arg))?;
+                settings.notify.command = Some(value.to_string());
+            }
+            "--scrollback" => {
+                let value = it
+                    .next()
+                    .ok_or_else(|| format!("{} requires a value", arg))?;
+                settings.scroll_back = value
+                    .parse::<usize>()
+                    .map_err(|_| format!("invalid scrollback `{}`", value))?;
+            }
+            "--history" => settings.history = true,
+            "--log-dir" => {
+                let value = it
+                    .next()
+                    .ok_or_else(|| format!("{} requires a value", arg))?;
+                settings.log_dir = Some(PathBuf::from(value));
+            }
+            "--log-size" => {
+                let value = it
+                    .next()
+                    .ok_or_else(|| format!("{} requires a value", arg))?;
+                settings.log_rotation.max_size = value
+                    .parse::<u64>()
+                    .ok()
+                    .and_then(|mb| mb.checked_mul(1024 * 1024))
+                    .ok_or_else(|| format!("invalid log size `{}`", value))?;
+            }
+            "--log-keep" => {
+                let value = it
+                    .next()
+                    .ok_or_else(|| format!("{} requires a value", arg))?;
+                settings.log_rotation.keep = value
+                    .parse::<usize>()
+                    .map_err(|_| format!("invalid log count `{}`", value))?;
+            }
+            "-e" | "--env" => {
+                let value = it
+                    .next()
+                    .ok_or_else(|| format!("{} requires a value", arg))?;
+                settings.env_files.push(PathBuf::from(value));
+            }
+            "-p" | "--port" => {
+                let value = it
+                    .next()
+                    .ok_or_else(|| format!("{} requires a value", arg))?;
+                settings.base_port = Some(
+                    value
+                        .parse::<u16>()
+                        .map_err(|_| format!("invalid port `{}`", value))?,
+                );
+            }
+            _ if arg.starts_with('-') => return Err(format!("unknown option `{}`", arg)),
+            _ => procfile_path = Some(PathBuf::from(arg)),
+        }
+    }
+    procfile_path
+        .map(|p| (command, p, settings))
+        .ok_or_else(|| "You must specify path to Procfile".to_string())
+}
+
+/// Identifies a Procfile by the SHA-256 of its canonical path, for its saved state and its
+/// control socket.
+fn procfile_hash(procfile_path: &Path) -> String {
+    let mut hasher = sha2::Sha256::new();
+    hasher.update(procfile_path.as_os_str().as_bytes());
+    let hash = hasher.finalize();
+    format!("{:x}", hash)
+}
+
+/// Loads the `.env` next to the Procfile, then the files given with `--env`.
+fn load_env(procfile_path: &Path, settings: &mut Settings) -> Result<(), dotenv::Error> {
+    let default_env_file = procfile_path.with_file_name(".env");
+    let env_files = Some(default_env_file)
+        .filter(|it| it.exists())
+        .into_iter()
+        .chain(settings.env_files.clone());
+    for path in env_files {
+        dotenv::load(&path, &mut settings.env)?;
+    }
+    Ok(())
+}
+
+/// Fails when a member of `procfile` would get a `PORT` above 65535.
+fn check_ports(procfile: &Procfile, settings: &Settings) -> Result<(), String> {
+    for group in procfile.iter() {
+        for i in 0..group.members.len() {
+            if group.port(i, settings).is_none() {
+                return Err(format!(
+                    "PORT of `{}` would be above 65535 with base port {}; use a lower --port",
+                    group.title,
+                    settings.base_port()
+                ));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Reports every problem of the Procfile and prints the groups it defines.
+/// Returns the exit code.
+fn check(procfile_path: &Path, settings: &mut Settings) -> i32 {
+    let (procfile, diagnostics) = match check_procfile(procfile_path) {
+        Ok(v) => v,
+        Err(e) => {
+            eprint!("{}", e);
+            return 1;
+        }
+    };
+
+    let mut errors = diagnostics
+        .iter()
+        .filter(|it| it.severity == Severity::Error)
+        .count();
+    let warnings = diagnostics.len() - errors;
+    for d in diagnostics.iter() {
+        eprintln!("{}", d.display(procfile_path));
+    }
+    if let Err(e) = load_env(procfile_path, settings) {
+        eprintln!("error: {}\n", e);
+        errors += 1;
+    }
+    if let Err(e) = check_ports(&procfile, settings) {
+        eprintln!("error: {}\n", e);
+        errors += 1;
+    }
+
+    for group in procfile.iter() {
+        let concurrent = if group.concurrent {
+            " (concurrent)"
+        } else {
+            ""
+        };
+        println!("{}{}", group.title, concurrent);
+        for (i, member) in group.members.iter().enumerate() {
+            if let Process::Command { label, argv, .. } = member {
+                let port = group.env(i, settings).remove("PORT").unwrap_or_default();
+                println!("  {}: {} [PORT={}] {}", i, label, port, argv);
+            }
+        }
+    }
+    println!();
+    println!("{} error(s), {} warning(s)", errors, warnings);
+
+    if errors > 0 {
+        1
+    } else {
+        0
+    }
+}
+
 fn main() -> Result<(), Error> {
     // simplelog::WriteLogger::init(
     //     simplelog::LevelFilter::Debug,
@@ -674,96 +2914,340 @@ fn main() -> Result<(), Error> {
     // .unwrap();
 
     let args: Vec<String> = std::env::args().collect();
-    if args.len() < 2 {
-        panic!("You must specify path to Procfile");
+    if args.get(1).map(|it| it.as_str()) == Some("ctl") {
+        std::process::exit(ctl::run(&args[2..]));
+    }
+    let (command, procfile_path, mut settings) = match parse_args(&args) {
+        Ok(v) => v,
+        Err(e) => {
+            eprintln!("sudare: {}", e);
+            eprintln!("{}", USAGE);
+            std::process::exit(2);
+        }
+    };
+
+    let procfile_path = procfile_path.canonicalize()?;
+    if let Command::Check = command {
+        std::process::exit(check(&procfile_path, &mut settings));
     }
+    let procfile_hash = procfile_hash(&procfile_path);
 
-    let procfile_path = Path::new(&args[1].as_str()).canonicalize()?;
-    let procfile_hash = {
-        let mut hasher = sha2::Sha256::new();
-        hasher.update(procfile_path.as_os_str().as_bytes());
-        let hash = hasher.finalize();
-        format!("{:x}", hash)
+    let procfile = match parse_procfile(procfile_path.as_path()) {
+        Ok(v) => v,
+        Err(e) => {
+            eprint!("{}", e);
+            std::process::exit(1);
+        }
     };
 
-    let procfile = parse_procfile(procfile_path.as_path())?;
+    if let Err(e) = load_env(&procfile_path, &mut settings) {
+        eprintln!("sudare: {}", e);
+        std::process::exit(1);
+    }
+    if let Err(e) = check_ports(&procfile, &settings) {
+        eprintln!("sudare: {}", e);
+        std::process::exit(1);
+    }
 
-    let pty_system = NativePtySystem::default();
+    if let Command::Run { abort_on_failure } = command {
+        std::process::exit(run::run(procfile, &settings, abort_on_failure));
+    }
 
-    let caps =
-        Capabilities::new_with_hints(ProbeHints::new_from_env().mouse_reporting(Some(false)))?;
+    let keymap = match Keymap::load(&Keymap::default_path()) {
+        Ok(v) => v,
+        Err(e) => {
+            eprintln!("sudare: {}", e);
+            std::process::exit(1);
+        }
+    };
 
-    let mut buf = BufferedTerminal::new(new_terminal(caps)?)?;
-    buf.terminal().set_raw_mode()?;
-    buf.terminal().enter_alternate_screen()?;
+    let attach_path = attach::socket_path(&procfile_hash);
+    if let Command::Start = command {
+        if !attach::is_running(&attach_path) {
+            if let Err(e) = attach::start_server(&attach_path, &args[1..]) {
+                eprintln!("sudare: {}", e);
+                std::process::exit(1);
+            }
+        }
+        match attach::attach(&attach_path) {
+            Ok(attach::Release::Detached) => {
+                println!("[detached from {}]", procfile_path.display())
+            }
+            Ok(attach::Release::Exited) => {}
+            Err(e) => {
+                eprintln!("sudare: {}", e);
+                std::process::exit(1);
+            }
+        }
+        return Ok(());
+    }
+
+    let mut listener = match attach::Listener::bind(&attach_path) {
+        Ok(v) => v,
+        Err(e) => {
+            eprintln!("sudare: {}", e);
+            std::process::exit(1);
+        }
+    };
+    let pty_system = NativePtySystem::default();
 
-    let mut ui_state = UiState::new(procfile_hash, procfile, buf.dimensions());
+    settings.history_dir = UiState::cache_dir().join("history").join(&procfile_hash);
+    let socket_path = control::socket_path(&procfile_hash);
+    let mut ui_state = UiState::new(
+        procfile_hash,
+        settings,
+        keymap,
+        procfile,
+        DETACHED_DIMENSION,
+    );
     ui_state.load_state(&pty_system)?;
+    let mut control = match control::Server::bind(&socket_path) {
+        Ok(server) => Some(server),
+        Err(e) => {
+            let message = format!("Control socket disabled: {}", e);
+            ui_state.message = Some((message, Instant::now()));
+            None
+        }
+    };
 
+    let mut attached: Option<(attach::Client, BufferedTerminal<UnixTerminal>)> = None;
     loop {
-        match buf.terminal().poll_input(Some(Duration::ZERO)) {
-            Ok(Some(InputEvent::Resized { rows, cols })) => {
+        if ui_state.is_quitting() && ui_state.is_stopped() {
+            break;
+        }
+        ui_state.supervise(&pty_system);
+        ui_state.advance();
+        if let Some(server) = control.as_mut() {
+            server.poll(|request| ui_state.handle_request(&pty_system, request));
+        }
+
+        match listener.accept() {
+            Ok(Some(client)) => match open_terminal(&client) {
+                Ok(buf) => {
+                    // A client attaching elsewhere takes over, like `tmux attach -d`.
+                    if let Some((client, buf)) = attached.take() {
+                        release(client, buf, attach::Release::Detached, false);
+                    }
+                    attached = Some((client, buf));
+                }
+                Err(e) => log::error!("attach error: {}", e),
+            },
+            Ok(None) => {}
+            Err(e) => log::error!("attach error: {}", e),
+        }
+        let (client, buf) = match attached.as_mut() {
+            Some((client, buf)) => (client, buf),
+            None => {
+                ui_state.send_notifications(None);
+                sleep(Duration::from_millis(10));
+                continue;
+            }
+        };
+        let mut detaching = false;
+        let mut gone = false;
+        match client.poll() {
+            // The server gets no SIGWINCH for the terminal of a client, which sends its size.
+            Ok(Some((cols, rows))) => {
                 // FIXME: this is working around a bug where we don't realize
                 // that we should redraw everything on resize in BufferedTerminal.
                 buf.add_change(Change::ClearScreen(Default::default()));
                 buf.resize(cols, rows);
             }
-            Ok(Some(input)) => match input {
-                InputEvent::Key(KeyEvent {
-                    key: KeyCode::Escape,
-                    ..
-                }) => {
-                    ui_state.save_state()?;
-                    break;
-                }
-                InputEvent::Key(KeyEvent {
-                    key: KeyCode::Char('n'),
-                    ..
-                })
-                | InputEvent::Key(KeyEvent {
-                    key: KeyCode::DownArrow,
-                    ..
-                }) => ui_state.next_window(),
-                InputEvent::Key(KeyEvent {
-                    key: KeyCode::Char('p'),
-                    ..
-                })
-                | InputEvent::Key(KeyEvent {
-                    key: KeyCode::UpArrow,
-                    ..
-                }) => ui_state.previous_window(),
-                InputEvent::Key(KeyEvent {
-                    key: KeyCode::Char(c),
-                    ..
-                }) if c.is_digit(10) => {
-                    ui_state.select_process(&pty_system, c.to_digit(10).unwrap() as usize)
-                }
-                InputEvent::Key(KeyEvent {
-                    key: KeyCode::Char('k'),
-                    ..
-                }) => {
-                    ui_state.scroll_up();
-                }
-                InputEvent::Key(KeyEvent {
-                    key: KeyCode::Char('j'),
-                    ..
-                }) => {
-                    ui_state.scroll_down();
+            Ok(None) => {}
+            Err(_) => {
+                detaching = true;
+                gone = true;
+            }
+        }
+
+        match buf.terminal().poll_input(Some(Duration::ZERO)) {
+            Ok(Some(InputEvent::Key(_))) if ui_state.is_help_open() => ui_state.close_help(),
+            Ok(Some(InputEvent::Key(key))) if ui_state.is_search_prompt_open() => {
+                ui_state.search_prompt_key(&key);
+            }
+            Ok(Some(InputEvent::Key(key))) if ui_state.is_copy_mode() => {
+                ui_state.copy_mode_key(buf.terminal(), &key);
+            }
+            Ok(Some(input)) if ui_state.input_mode == InputMode::Passthrough => match input {
+                InputEvent::Key(key) => {
+                    match ui_state
+                        .keymap
+                        .feed(&key, |action| action == Action::InsertMode)
+                    {
+                        Lookup::Action(action) => ui_state.perform(&pty_system, action),
+                        Lookup::Pending => {}
+                        Lookup::Unbound(keys) => keys.iter().for_each(|k| ui_state.send_key(k)),
+                    }
                 }
+                InputEvent::Paste(text) => ui_state.send_paste(&text),
                 _ => {}
             },
+            Ok(Some(InputEvent::Key(key))) if ui_state.is_search_key(&key) => {
+                ui_state.search_key(&key);
+            }
+            Ok(Some(InputEvent::Key(key))) => match ui_state.keymap.feed(&key, |_| true) {
+                Lookup::Action(Action::Detach) => detaching = true,
+                Lookup::Action(Action::Quit) => {
+                    if ui_state.is_quitting() {
+                        // Pressed again while waiting: don't wait for the grace period.
+                        break;
+                    }
+                    ui_state.save_state()?;
+                    ui_state.quit();
+                }
+                Lookup::Action(action) => ui_state.perform(&pty_system, action),
+                Lookup::Pending | Lookup::Unbound(_) => {}
+            },
+            Ok(Some(_)) => {}
             Ok(None) => {}
             Err(e) => {
-                print!("{:?}\r\n", e);
-                break;
+                log::error!("terminal error: {}", e);
+                detaching = true;
+                gone = true;
             }
         }
+        if detaching {
+            if let Some((client, buf)) = attached.take() {
+                release(client, buf, attach::Release::Detached, gone);
+            }
+            continue;
+        }
 
-        ui_state.render_to_screen(&mut buf);
-        buf.flush().unwrap();
+        ui_state.render_to_screen(buf);
+        if let Err(e) = buf.flush() {
+            log::error!("terminal error: {}", e);
+        }
+        ui_state.send_notifications(Some(buf.terminal()));
 
         sleep(Duration::from_millis(10));
     }
 
+    if let Some((client, buf)) = attached.take() {
+        release(client, buf, attach::Release::Exited, false);
+    }
     Ok(())
 }
+
+/// Takes over the terminal of a client.
+fn open_terminal(client: &attach::Client) -> Result<BufferedTerminal<UnixTerminal>, Error> {
+    let mut buf = BufferedTerminal::new(client.terminal()?)?;
+    buf.terminal().set_raw_mode()?;
+    buf.terminal().enter_alternate_screen()?;
+    Ok(buf)
+}
+
+/// Restores the terminal of a client, unless the client is `gone`, and lets it exit.
+fn release(
+    client: attach::Client,
+    mut buf: BufferedTerminal<UnixTerminal>,
+    release: attach::Release,
+    gone: bool,
+) {
+    if !gone {
+        let terminal = buf.terminal();
+        let cooked = terminal.set_cooked_mode();
+        let exited = terminal.exit_alternate_screen();
+        if let Err(e) = cooked.and(exited).and(terminal.flush()) {
+            log::error!("terminal error: {}", e);
+        }
+    }
+    // Dropping a terminal restores it again and panics when it can't, e.g. once the SSH
+    // connection of the client is gone. Its descriptors are closed while unwinding, but the
+    // panic comes before it unregisters its SIGWINCH handler, whose id it keeps to itself.
+    // No terminal needs one, as clients send their size, so remove them all.
+    let hook = std::panic::take_hook();
+    std::panic::set_hook(Box::new(|info| log::error!("terminal error: {}", info)));
+    let dropped = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| drop(buf)));
+    std::panic::set_hook(hook);
+    if dropped.is_err() {
+        #[allow(deprecated)]
+        signal_hook_registry::unregister_signal(libc::SIGWINCH);
+    }
+    client.release(release);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn group(index: usize, members: usize, concurrent: bool) -> ProcessGroup {
+        ProcessGroup {
+            title: format!("group-{}", index),
+            index,
+            members: vec![Process::Null; members],
+            concurrent,
+        }
+    }
+
+    fn with_base_port(base_port: u16) -> Settings {
+        Settings {
+            base_port: Some(base_port),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn port_steps_by_group_and_by_concurrent_member() {
+        let settings = with_base_port(5000);
+        let ports = |g: &ProcessGroup| {
+            (0..g.members.len())
+                .map(|i| g.port(i, &settings))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            ports(&group(2, 3, false)),
+            vec![Some(5200), Some(5200), Some(5200)]
+        );
+        assert_eq!(
+            ports(&group(2, 3, true)),
+            vec![Some(5200), Some(5200), Some(5201)]
+        );
+    }
+
+    #[test]
+    fn port_takes_the_base_port_from_the_dotenv_files() {
+        let mut settings = Settings::default();
+        settings.env.insert("PORT".to_string(), "3000".to_string());
+        assert_eq!(settings.base_port(), 3000);
+        assert_eq!(group(1, 2, false).port(1, &settings), Some(3100));
+        settings.base_port = Some(4000);
+        assert_eq!(settings.base_port(), 4000);
+    }
+
+    #[test]
+    fn port_above_65535_is_rejected() {
+        let settings = with_base_port(65400);
+        assert_eq!(group(1, 2, false).port(1, &settings), Some(65500));
+        assert_eq!(group(2, 2, false).port(1, &settings), None);
+        assert_eq!(group(0, 3, true).port(2, &with_base_port(u16::MAX)), None);
+
+        assert_eq!(check_ports(&vec![group(1, 2, false)], &settings), Ok(()));
+        assert_eq!(
+            check_ports(&vec![group(1, 2, false), group(2, 2, false)], &settings),
+            Err(
+                "PORT of `group-2` would be above 65535 with base port 65400; use a lower --port"
+                    .to_string()
+            )
+        );
+    }
+
+    #[test]
+    fn restart_backoff_doubles_up_to_a_minute() {
+        let delays = (0..8)
+            .map(|count| restart_backoff(count, Duration::ZERO).1.as_secs())
+            .collect::<Vec<_>>();
+        assert_eq!(delays, vec![1, 2, 4, 8, 16, 32, 60, 60]);
+        assert_eq!(
+            restart_backoff(u32::MAX, Duration::ZERO),
+            (u32::MAX, RESTART_BACKOFF_MAX)
+        );
+    }
+
+    #[test]
+    fn restart_backoff_starts_over_after_a_long_run() {
+        let short = Duration::from_secs(59);
+        assert_eq!(restart_backoff(5, short), (5, Duration::from_secs(32)));
+        let long = Duration::from_secs(60);
+        assert_eq!(restart_backoff(5, long), (0, Duration::from_secs(1)));
+    }
+}
@@ -0,0 +1,81 @@
+use std::io;
+use std::process::{Command, Stdio};
+
+use regex::Regex;
+use termwiz::escape::OperatingSystemCommand;
+use termwiz::surface::Change;
+use termwiz::terminal::Terminal;
+
+/// When notifications are sent and how, from the `--notify` options.
+#[derive(Debug, Clone, Default)]
+pub struct Notify {
+    /// A process exited on its own.
+    pub exit: bool,
+    /// A process exited with a non-zero code.
+    pub failure: bool,
+    /// A line of output matched.
+    pub pattern: Option<Regex>,
+    /// Run with `sh -c` for every notification.
+    pub command: Option<String>,
+}
+
+impl Notify {
+    /// Enables a trigger given as `exit`, `failure` or `match=REGEX`.
+    pub fn add_trigger(&mut self, trigger: &str) -> Result<(), String> {
+        match trigger.split_once('=') {
+            Some(("match", pattern)) => {
+                let regex = Regex::new(pattern)
+                    .map_err(|e| format!("invalid notify pattern `{}`: {}", pattern, e))?;
+                self.pattern = Some(regex);
+            }
+            None if trigger == "exit" => self.exit = true,
+            None if trigger == "failure" => self.failure = true,
+            _ => return Err(format!("unknown notify trigger `{}`", trigger)),
+        }
+        Ok(())
+    }
+
+    /// Whether a process exiting with `code` is notified.
+    pub fn on_exit(&self, code: u32) -> bool {
+        self.exit || (self.failure && code != 0)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Notification {
+    pub title: String,
+    pub body: String,
+}
+
+/// Rings the bell of the terminal sudare runs in and asks it to show a desktop notification,
+/// with OSC 9 (iTerm2, kitty, WezTerm, ...) and OSC 777 (rxvt, foot, ...).
+pub fn send(terminal: &mut impl Terminal, notification: &Notification) -> termwiz::Result<()> {
+    let osc9 = OperatingSystemCommand::SystemNotification(format!(
+        "{}: {}",
+        notification.title, notification.body
+    ));
+    // Fields of OSC 777 are separated by semicolons.
+    let osc777 = OperatingSystemCommand::RxvtExtension(vec![
+        "notify".to_string(),
+        notification.title.replace(';', ","),
+        notification.body.replace(';', ","),
+    ]);
+    terminal.render(&[Change::Text(format!("\u{7}{}{}", osc9, osc777))])?;
+    terminal.flush()
+}
+
+/// Runs `command` in the background with the notification in `SUDARE_TITLE` and
+/// `SUDARE_BODY`.
+pub fn run_command(command: &str, notification: &Notification) -> io::Result<()> {
+    let mut child = Command::new("sh")
+        .args(["-c", command])
+        .env("SUDARE_TITLE", &notification.title)
+        .env("SUDARE_BODY", &notification.body)
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()?;
+    // Reap it without blocking the UI.
+    std::thread::spawn(move || child.wait());
+    Ok(())
+}
@@ -0,0 +1,594 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+pub type Procfile = Vec<ProcessGroup>;
+
+#[derive(Debug, Clone)]
+pub struct ProcessGroup {
+    pub title: String,
+    /// Position in the Procfile, used to assign `PORT`.
+    pub index: usize,
+    pub members: Vec<Process>,
+    /// Members keep running when another one is displayed.
+    pub concurrent: bool,
+}
+
+#[derive(Debug, Clone)]
+pub enum Process {
+    Null,
+    Command {
+        label: String,
+        argv: String,
+        options: ProcessOptions,
+    },
+}
+
+pub const DEFAULT_TITLE: &str = "disable";
+pub const DEFAULT_LABEL: &str = "default";
+
+impl Process {
+    pub fn label(&self) -> String {
+        match self {
+            Process::Null => DEFAULT_TITLE.to_string(),
+            Process::Command { label, .. } => label.to_string(),
+        }
+    }
+}
+
+/// Per-process options given in parentheses after the process name, e.g. `web[puma](signal=INT)`.
+#[derive(Debug, Clone, Default)]
+pub struct ProcessOptions {
+    pub stop_signal: Option<libc::c_int>,
+    pub stop_timeout: Option<Duration>,
+    pub concurrent: bool,
+    pub restart: RestartPolicy,
+    pub max_retries: Option<u32>,
+    /// Number of lines kept in memory for scrolling back.
+    pub scroll_back: Option<usize>,
+    /// Save lines that no longer fit in the scrollback to a file.
+    pub history: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum RestartPolicy {
+    #[default]
+    Never,
+    OnFailure,
+    Always,
+}
+
+const KNOWN_OPTIONS: &[&str] = &[
+    "signal",
+    "timeout",
+    "concurrent",
+    "restart",
+    "max-retries",
+    "scrollback",
+    "history",
+];
+
+/// A message about a byte range of the text being parsed.
+type Spanned = (Range<usize>, String);
+
+impl ProcessOptions {
+    /// Parses a comma separated option list, returning warnings about ignored options.
+    /// Spans are byte ranges within `s`.
+    fn parse(s: &str) -> Result<(Self, Vec<Spanned>), Spanned> {
+        let mut options = ProcessOptions::default();
+        let mut warnings = Vec::new();
+        let mut offset = 0;
+        for raw in s.split(',') {
+            let start = offset + (raw.len() - raw.trim_start().len());
+            let item = raw.trim();
+            let span = start..start + item.len();
+            offset += raw.len() + 1;
+            if item.is_empty() {
+                continue;
+            }
+            let key = item.split('=').next().unwrap_or_default().trim();
+            if !KNOWN_OPTIONS.contains(&key) {
+                warnings.push((span, format!("unknown option `{}` is ignored", key)));
+                continue;
+            }
+            options.apply(item).map_err(|message| (span, message))?;
+        }
+        Ok((options, warnings))
+    }
+
+    fn apply(&mut self, item: &str) -> Result<(), String> {
+        match item {
+            "concurrent" => {
+                self.concurrent = true;
+                return Ok(());
+            }
+            "history" => {
+                self.history = true;
+                return Ok(());
+            }
+            _ => {}
+        }
+        let (key, value) = item
+            .split_once('=')
+            .map(|(k, v)| (k.trim(), v.trim()))
+            .ok_or_else(|| format!("expected `key=value`, found `{}`", item))?;
+        match key {
+            "signal" => {
+                self.stop_signal =
+                    Some(parse_signal(value).ok_or_else(|| format!("unknown signal `{}`", value))?)
+            }
+            "timeout" => {
+                self.stop_timeout = Some(
+                    value
+                        .parse::<u64>()
+                        .map(Duration::from_secs)
+                        .map_err(|_| format!("invalid timeout `{}`", value))?,
+                )
+            }
+            "restart" => {
+                self.restart = match value {
+                    "never" => RestartPolicy::Never,
+                    "on-failure" => RestartPolicy::OnFailure,
+                    "always" => RestartPolicy::Always,
+                    _ => return Err(format!("unknown restart policy `{}`", value)),
+                }
+            }
+            "max-retries" => {
+                self.max_retries = Some(
+                    value
+                        .parse::<u32>()
+                        .map_err(|_| format!("invalid max-retries `{}`", value))?,
+                )
+            }
+            "scrollback" => {
+                self.scroll_back = Some(
+                    value
+                        .parse::<usize>()
+                        .map_err(|_| format!("invalid scrollback `{}`", value))?,
+                )
+            }
+            _ => return Err(format!("unknown option `{}`", key)),
+        }
+        Ok(())
+    }
+}
+
+pub fn parse_signal(s: &str) -> Option<libc::c_int> {
+    if let Ok(n) = s.parse::<libc::c_int>() {
+        return Some(n);
+    }
+    match s.trim_start_matches("SIG") {
+        "HUP" => Some(libc::SIGHUP),
+        "INT" => Some(libc::SIGINT),
+        "QUIT" => Some(libc::SIGQUIT),
+        "KILL" => Some(libc::SIGKILL),
+        "USR1" => Some(libc::SIGUSR1),
+        "USR2" => Some(libc::SIGUSR2),
+        "TERM" => Some(libc::SIGTERM),
+        _ => None,
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+/// A problem found at a specific place of the Procfile.
+#[derive(Debug)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    /// 1-based line number.
+    pub line: usize,
+    /// Byte range within `source` to underline.
+    pub span: Range<usize>,
+    pub source: String,
+    pub message: String,
+    pub note: Option<String>,
+}
+
+impl Diagnostic {
+    /// 1-based column of the start of the span.
+    pub fn column(&self) -> usize {
+        self.source[..self.span.start].chars().count() + 1
+    }
+
+    pub fn display<'a>(&'a self, path: &'a Path) -> impl fmt::Display + 'a {
+        DiagnosticDisplay(self, path)
+    }
+
+    fn render(&self, f: &mut fmt::Formatter<'_>, path: &Path) -> fmt::Result {
+        let gutter = self.line.to_string().len();
+        let carets = self.source[self.span.clone()].chars().count().max(1);
+        let severity = match self.severity {
+            Severity::Error => "error",
+            Severity::Warning => "warning",
+        };
+        writeln!(f, "{}: {}", severity, self.message)?;
+        writeln!(
+            f,
+            "{:>w$}--> {}:{}:{}",
+            "",
+            path.display(),
+            self.line,
+            self.column(),
+            w = gutter
+        )?;
+        writeln!(f, "{:>w$} |", "", w = gutter)?;
+        writeln!(f, "{} | {}", self.line, self.source)?;
+        writeln!(
+            f,
+            "{:>w$} | {}{}",
+            "",
+            " ".repeat(self.column() - 1),
+            "^".repeat(carets),
+            w = gutter
+        )?;
+        if let Some(note) = &self.note {
+            writeln!(f, "{:>w$} = note: {}", "", note, w = gutter)?;
+        }
+        Ok(())
+    }
+}
+
+struct DiagnosticDisplay<'a>(&'a Diagnostic, &'a Path);
+
+impl fmt::Display for DiagnosticDisplay<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.render(f, self.1)
+    }
+}
+
+#[derive(Debug)]
+pub enum Error {
+    Io(PathBuf, io::Error),
+    Invalid(PathBuf, Vec<Diagnostic>),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Io(path, e) => writeln!(f, "error: {}: {}", path.display(), e),
+            Error::Invalid(path, diagnostics) => {
+                for (i, d) in diagnostics.iter().enumerate() {
+                    if i > 0 {
+                        writeln!(f)?;
+                    }
+                    d.render(f, path)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+/// A parsed `name[label](options): command` line.
+struct Entry {
+    line: usize,
+    source: String,
+    title: String,
+    label: String,
+    /// Byte range of `name[label]` in the line.
+    title_span: Range<usize>,
+    argv: String,
+    options: ProcessOptions,
+    warnings: Vec<Spanned>,
+}
+
+impl Entry {
+    fn diagnostic(
+        &self,
+        severity: Severity,
+        span: Range<usize>,
+        message: String,
+        note: Option<String>,
+    ) -> Diagnostic {
+        Diagnostic {
+            severity,
+            line: self.line,
+            span,
+            source: self.source.clone(),
+            message,
+            note,
+        }
+    }
+}
+
+/// Digit keys select members 0 to 9, and member 0 is always `disable`.
+const MAX_SELECTABLE_MEMBERS: usize = 9;
+
+/// Parses the Procfile, failing if it has any error. Warnings are ignored.
+pub fn parse_procfile(path: &Path) -> Result<Procfile, Error> {
+    let (procfile, diagnostics) = check_procfile(path)?;
+    if diagnostics.iter().any(|it| it.severity == Severity::Error) {
+        return Err(Error::Invalid(path.to_path_buf(), diagnostics));
+    }
+    Ok(procfile)
+}
+
+/// Parses as much of the Procfile as possible, collecting every error and warning.
+/// Lines with errors are left out of the returned Procfile.
+pub fn check_procfile(path: &Path) -> Result<(Procfile, Vec<Diagnostic>), Error> {
+    let reader = BufReader::new(File::open(path).map_err(|e| Error::Io(path.to_path_buf(), e))?);
+
+    let mut entries = Vec::<Entry>::new();
+    let mut diagnostics = Vec::<Diagnostic>::new();
+    for (i, l) in reader.lines().enumerate() {
+        let l = l.map_err(|e| Error::Io(path.to_path_buf(), e))?;
+        let trimmed = l.trim();
+        if trimmed.is_empty() || trimmed.starts_with('#') {
+            continue;
+        }
+        match parse_entry(i + 1, &l) {
+            Ok(entry) => {
+                diagnostics.extend(entry.warnings.iter().map(|(span, message)| {
+                    entry.diagnostic(Severity::Warning, span.clone(), message.clone(), None)
+                }));
+                if entry.argv.is_empty() {
+                    diagnostics.push(entry.diagnostic(
+                        Severity::Warning,
+                        entry.title_span.clone(),
+                        format!("`{}[{}]` has an empty command", entry.title, entry.label),
+                        None,
+                    ));
+                }
+                if let Some(first) = entries
+                    .iter()
+                    .find(|it| it.title == entry.title && it.label == entry.label)
+                {
+                    diagnostics.push(entry.diagnostic(
+                        Severity::Error,
+                        entry.title_span.clone(),
+                        format!("duplicate process `{}[{}]`", entry.title, entry.label),
+                        Some(format!("first defined on line {}", first.line)),
+                    ));
+                } else {
+                    let position = entries.iter().filter(|it| it.title == entry.title).count();
+                    if position == MAX_SELECTABLE_MEMBERS {
+                        diagnostics.push(entry.diagnostic(
+                            Severity::Warning,
+                            entry.title_span.clone(),
+                            format!(
+                                "group `{}` has more than {} members",
+                                entry.title, MAX_SELECTABLE_MEMBERS
+                            ),
+                            Some(
+                                "this and the following members cannot be selected with digit keys"
+                                    .to_string(),
+                            ),
+                        ));
+                    }
+                    entries.push(entry);
+                }
+            }
+            Err((span, message)) => diagnostics.push(Diagnostic {
+                severity: Severity::Error,
+                line: i + 1,
+                span,
+                source: l.clone(),
+                message,
+                note: None,
+            }),
+        }
+    }
+
+    let mut ordered = Vec::<String>::new();
+    let mut map = BTreeMap::<String, Vec<Entry>>::new();
+    for entry in entries {
+        if !ordered.contains(&entry.title) {
+            ordered.push(entry.title.clone());
+        }
+        map.entry(entry.title.clone()).or_default().push(entry);
+    }
+
+    let r: Procfile = ordered
+        .iter()
+        .map(|title| (title, map.remove(title).unwrap()))
+        .enumerate()
+        .map(|(index, (title, members))| ProcessGroup {
+            title: title.clone(),
+            index,
+            concurrent: members.iter().any(|it| it.options.concurrent),
+            members: vec![Process::Null]
+                .into_iter()
+                .chain(members.into_iter().map(|it| Process::Command {
+                    label: it.label,
+                    argv: it.argv,
+                    options: it.options,
+                }))
+                .collect(),
+        })
+        .collect();
+    Ok((r, diagnostics))
+}
+
+fn is_valid_name(s: &str) -> bool {
+    !s.is_empty()
+        && s.chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.')
+}
+
+/// Parses `name[label](options): command`. On error, returns the byte range to underline.
+fn parse_entry(line: usize, l: &str) -> Result<Entry, (Range<usize>, String)> {
+    let colon = l
+        .find(':')
+        .ok_or_else(|| (0..l.len(), "expected `name: command`".to_string()))?;
+    let start = l.len() - l.trim_start().len();
+    let head = &l[..colon];
+    let end = head.trim_end().len();
+    if start > end {
+        // Nothing but spaces before the colon.
+        return Err((start..start + 1, "invalid process name ``".to_string()));
+    }
+
+    let name_end = head[start..end]
+        .find(['[', '('])
+        .map(|i| start + i)
+        .unwrap_or(end);
+    let title = head[start..name_end].trim_end();
+    if !is_valid_name(title) {
+        return Err((
+            start..start + title.len().max(1),
+            format!("invalid process name `{}`", title),
+        ));
+    }
+
+    let mut pos = name_end;
+    let label = if head[pos..end].starts_with('[') {
+        let close = head[pos..end]
+            .find(']')
+            .map(|i| pos + i)
+            .ok_or_else(|| (pos..end, "unclosed `[`".to_string()))?;
+        let label = &head[pos + 1..close];
+        if !is_valid_name(label) {
+            return Err((pos..close + 1, format!("invalid process label `{}`", label)));
+        }
+        pos = close + 1;
+        label
+    } else {
+        DEFAULT_LABEL
+    };
+    let title_span = start..pos;
+
+    pos += head[pos..end].len() - head[pos..end].trim_start().len();
+    let (options, warnings) = if head[pos..end].starts_with('(') {
+        if !head[pos..end].ends_with(')') {
+            return Err((pos..end, "unclosed `(`".to_string()));
+        }
+        let options_start = pos + 1;
+        let shift = |span: Range<usize>| options_start + span.start..options_start + span.end;
+        let (options, warnings) = ProcessOptions::parse(&head[options_start..end - 1])
+            .map_err(|(span, message)| (shift(span), message))?;
+        pos = end;
+        (
+            options,
+            warnings
+                .into_iter()
+                .map(|(span, message)| (shift(span), message))
+                .collect(),
+        )
+    } else {
+        (ProcessOptions::default(), Vec::new())
+    };
+
+    if pos < end {
+        return Err((
+            pos..end,
+            format!("unexpected `{}` after process name", &head[pos..end]),
+        ));
+    }
+
+    Ok(Entry {
+        line,
+        source: l.to_string(),
+        title: title.to_string(),
+        label: label.to_string(),
+        title_span,
+        argv: l[colon + 1..].trim().to_string(),
+        options,
+        warnings,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn check(source: &str) -> (Procfile, Vec<Diagnostic>) {
+        let path = std::env::temp_dir().join(format!(
+            "sudare-procfile-test-{}-{:?}",
+            std::process::id(),
+            std::thread::current().id()
+        ));
+        std::fs::write(&path, source).unwrap();
+        let result = check_procfile(&path);
+        std::fs::remove_file(&path).unwrap();
+        result.unwrap()
+    }
+
+    fn error(l: &str) -> (Range<usize>, String) {
+        match parse_entry(1, l) {
+            Ok(_) => panic!("`{}` should not parse", l),
+            Err(e) => e,
+        }
+    }
+
+    #[test]
+    fn parses_name_label_and_options() {
+        let entry = parse_entry(1, "web[dev](restart=always, concurrent): rails s").unwrap();
+        assert_eq!(entry.title, "web");
+        assert_eq!(entry.label, "dev");
+        assert_eq!(entry.argv, "rails s");
+        assert_eq!(entry.options.restart, RestartPolicy::Always);
+        assert!(entry.options.concurrent);
+    }
+
+    #[test]
+    fn reports_empty_name() {
+        assert_eq!(
+            error(": cmd"),
+            (0..1, "invalid process name ``".to_string())
+        );
+    }
+
+    #[test]
+    fn reports_indented_empty_name() {
+        assert_eq!(
+            error("   : cmd"),
+            (3..4, "invalid process name ``".to_string())
+        );
+    }
+
+    #[test]
+    fn reports_unclosed_brackets() {
+        assert_eq!(error("web[dev: cmd"), (3..7, "unclosed `[`".to_string()));
+        assert_eq!(
+            error("web(restart=always: cmd"),
+            (3..18, "unclosed `(`".to_string())
+        );
+    }
+
+    #[test]
+    fn skips_comments_and_blank_lines() {
+        let (procfile, diagnostics) = check("# web: cmd\n\n  #worker[x]: cmd\nweb: cmd\n");
+        assert_eq!(procfile.len(), 1);
+        assert_eq!(procfile[0].title, "web");
+        assert!(diagnostics.is_empty());
+    }
+
+    #[test]
+    fn warns_about_unknown_options() {
+        let (procfile, diagnostics) = check("web(colour=red): cmd\n");
+        assert_eq!(procfile.len(), 1);
+        assert_eq!(diagnostics.len(), 1);
+        let d = &diagnostics[0];
+        assert_eq!(d.severity, Severity::Warning);
+        assert_eq!(d.message, "unknown option `colour` is ignored");
+        assert_eq!((d.line, d.column()), (1, 5));
+    }
+
+    #[test]
+    fn collects_errors_of_every_line() {
+        let (procfile, diagnostics) = check("   : cmd\nweb: cmd\n# comment\nworker[x: cmd\n");
+        assert_eq!(procfile.len(), 1);
+        assert_eq!(procfile[0].title, "web");
+        let errors = diagnostics
+            .iter()
+            .map(|d| (d.severity, d.line, d.column(), d.message.as_str()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            errors,
+            vec![
+                (Severity::Error, 1, 4, "invalid process name ``"),
+                (Severity::Error, 4, 7, "unclosed `[`"),
+            ]
+        );
+    }
+}
@@ -0,0 +1,188 @@
+use std::io::{self, Write};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread::sleep;
+use std::time::{Duration, SystemTime};
+
+use portable_pty::NativePtySystem;
+
+use crate::procfile::{Process, Procfile, DEFAULT_LABEL};
+use crate::{timestamp, PtyProcess, Settings};
+
+/// Size of the pty given to processes, as there is no screen to follow.
+const DIMENSION: (usize, usize) = (80, 24);
+
+const COLORS: &[&str] = &[
+    "\x1b[36m", "\x1b[33m", "\x1b[32m", "\x1b[35m", "\x1b[34m", "\x1b[91m", "\x1b[96m", "\x1b[93m",
+    "\x1b[92m", "\x1b[95m", "\x1b[94m",
+];
+const RESET: &str = "\x1b[0m";
+
+/// Number of SIGINT/SIGTERM received.
+static TERMINATE: AtomicUsize = AtomicUsize::new(0);
+
+extern "C" fn on_terminate(_: libc::c_int) {
+    TERMINATE.fetch_add(1, Ordering::SeqCst);
+}
+
+struct Runner {
+    name: String,
+    color: &'static str,
+    process: PtyProcess,
+    /// Output received after the last newline.
+    partial: Vec<u8>,
+    exited: bool,
+}
+
+/// Prints the output of every process line by line with a `time group[label] |` prefix,
+/// like `foreman start`.
+struct Printer {
+    width: usize,
+    colored: bool,
+}
+
+impl Printer {
+    fn print(&self, name: &str, color: &str, line: &[u8]) {
+        let time = timestamp::format_local(SystemTime::now(), false);
+        let mut out = io::stdout().lock();
+        let r = if self.colored {
+            write!(
+                out,
+                "{}{} {:<w$} |{} ",
+                color,
+                time,
+                name,
+                RESET,
+                w = self.width
+            )
+        } else {
+            write!(out, "{} {:<w$} | ", time, name, w = self.width)
+        };
+        let r = r
+            .and_then(|_| out.write_all(line))
+            .and_then(|_| out.write_all(b"\n"));
+        if let Err(e) = r {
+            log::error!("write error: {}", e);
+        }
+    }
+
+    /// Prints every complete line of `runner`, and the rest too with `flush`.
+    fn drain(&self, runner: &mut Runner, flush: bool) {
+        while let Some(i) = runner.partial.iter().position(|b| *b == b'\n') {
+            let line = runner.partial.drain(..=i).collect::<Vec<_>>();
+            self.print(&runner.name, runner.color, trim_line(&line));
+        }
+        if flush && !runner.partial.is_empty() {
+            let line = std::mem::take(&mut runner.partial);
+            self.print(&runner.name, runner.color, trim_line(&line));
+        }
+    }
+}
+
+fn trim_line(line: &[u8]) -> &[u8] {
+    let line = line.strip_suffix(b"\n").unwrap_or(line);
+    line.strip_suffix(b"\r").unwrap_or(line)
+}
+
+/// The member labelled `default`, otherwise the first one.
+fn default_member(members: &[Process]) -> Option<usize> {
+    members
+        .iter()
+        .position(|it| matches!(it, Process::Command { label, .. } if label == DEFAULT_LABEL))
+        .or_else(|| members.iter().position(|it| !matches!(it, Process::Null)))
+}
+
+/// Runs the default member of each group without the terminal multiplexer and returns the
+/// exit code. With `abort_on_failure`, everything is stopped as soon as a process fails, and
+/// its exit code is returned.
+pub fn run(procfile: Procfile, settings: &Settings, abort_on_failure: bool) -> i32 {
+    let handler: extern "C" fn(libc::c_int) = on_terminate;
+    unsafe {
+        libc::signal(libc::SIGINT, handler as libc::sighandler_t);
+        libc::signal(libc::SIGTERM, handler as libc::sighandler_t);
+    }
+
+    let pty_system = NativePtySystem::default();
+    let mut runners = Vec::<Runner>::new();
+    for (n, group) in procfile.iter().enumerate() {
+        if let Some(i) = default_member(&group.members) {
+            if let Process::Command {
+                label,
+                argv,
+                options,
+            } = &group.members[i]
+            {
+                let name = if label == DEFAULT_LABEL {
+                    group.title.clone()
+                } else {
+                    format!("{}[{}]", group.title, label)
+                };
+                match PtyProcess::new(
+                    &pty_system,
+                    DIMENSION,
+                    argv,
+                    &group.env(i, settings),
+                    options.stop_policy(settings),
+                    group.log(i, settings),
+                ) {
+                    Ok(process) => runners.push(Runner {
+                        name,
+                        color: COLORS[n % COLORS.len()],
+                        process,
+                        partial: Vec::new(),
+                        exited: false,
+                    }),
+                    Err(e) => {
+                        eprintln!("sudare: failed to start {}: {}", name, e);
+                        return 1;
+                    }
+                }
+            }
+        }
+    }
+
+    let printer = Printer {
+        width: runners.iter().map(|it| it.name.len()).max().unwrap_or(0),
+        colored: unsafe { libc::isatty(libc::STDOUT_FILENO) } == 1
+            && std::env::var_os("NO_COLOR").is_none(),
+    };
+
+    let mut exit_code = None;
+    let mut stopping = false;
+    loop {
+        for runner in runners.iter_mut() {
+            let mut bytes = runner.process.poll();
+            runner.partial.append(&mut bytes);
+            let exit_status = runner.process.exit_status().cloned();
+            printer.drain(runner, exit_status.is_some());
+
+            if let Some(status) = exit_status {
+                if !runner.exited {
+                    runner.exited = true;
+                    if abort_on_failure && !status.success() && exit_code.is_none() {
+                        exit_code = Some(status.exit_code() as i32);
+                    }
+                }
+            }
+        }
+
+        let terminate = TERMINATE.load(Ordering::SeqCst);
+        if terminate > 1 {
+            // Interrupted again while stopping: processes are killed when dropped.
+            break;
+        }
+        if !stopping && (terminate > 0 || exit_code.is_some()) {
+            stopping = true;
+            runners.iter_mut().for_each(|it| it.process.stop());
+        }
+        if runners.iter().all(|it| it.process.is_stopped()) {
+            break;
+        }
+
+        sleep(Duration::from_millis(10));
+    }
+
+    runners
+        .iter_mut()
+        .for_each(|runner| printer.drain(runner, true));
+    exit_code.unwrap_or(0)
+}
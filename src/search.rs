@@ -0,0 +1,194 @@
+use std::fmt;
+use std::ops::Range;
+
+use regex::{Regex, RegexBuilder};
+use termwiz::color::{AnsiColor, ColorAttribute};
+use termwiz::surface::Line;
+use wezterm_term::StableRowIndex;
+
+/// Query being typed after `/`.
+#[derive(Debug, Default)]
+pub struct SearchPrompt {
+    pub input: String,
+    pub regex: bool,
+    pub error: Option<String>,
+}
+
+impl SearchPrompt {
+    /// Compiles the query, or returns `None` for an empty one. Queries without uppercase
+    /// letters are case insensitive.
+    pub fn compile(&self) -> Result<Option<Regex>, regex::Error> {
+        if self.input.is_empty() {
+            return Ok(None);
+        }
+        let pattern = if self.regex {
+            self.input.clone()
+        } else {
+            regex::escape(&self.input)
+        };
+        RegexBuilder::new(&pattern)
+            .case_insensitive(!self.input.chars().any(char::is_uppercase))
+            .build()
+            .map(Some)
+    }
+}
+
+impl fmt::Display for SearchPrompt {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mode = if self.regex { "regex" } else { "text" };
+        write!(f, "({}, C-r to toggle) /{}", mode, self.input)?;
+        if let Some(e) = &self.error {
+            write!(f, "  [{}]", e)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SearchMatch {
+    pub row: StableRowIndex,
+    /// Cell indices within the row.
+    pub cells: Range<usize>,
+}
+
+pub struct Search {
+    pub regex: Regex,
+    /// Every match in the screen and scrollback, oldest first.
+    pub matches: Vec<SearchMatch>,
+    pub current: Option<usize>,
+    /// Rows from here on may have changed since they were searched, or every row if `None`.
+    pub scanned: Option<StableRowIndex>,
+}
+
+impl Search {
+    pub fn new(regex: Regex) -> Self {
+        Self {
+            regex,
+            matches: Vec::new(),
+            current: None,
+            scanned: None,
+        }
+    }
+
+    pub fn current_match(&self) -> Option<&SearchMatch> {
+        self.current.and_then(|i| self.matches.get(i))
+    }
+
+    /// Keeps the matches in the rows of `kept` and adds `found` after them, keeping the current
+    /// match selected if it is still there. Rows from `scanned` on are searched again next time.
+    pub fn update(
+        &mut self,
+        kept: Range<StableRowIndex>,
+        found: Vec<SearchMatch>,
+        scanned: StableRowIndex,
+    ) {
+        let current = self.current_match().cloned();
+        self.matches.retain(|it| kept.contains(&it.row));
+        self.matches.extend(found);
+        self.current = current.and_then(|m| self.matches.iter().position(|it| *it == m));
+        self.scanned = Some(scanned);
+    }
+
+    /// Selects the next match towards older output when `older`, otherwise towards newer,
+    /// wrapping around at either end.
+    pub fn step(&mut self, older: bool) -> Option<&SearchMatch> {
+        let len = self.matches.len();
+        if len == 0 {
+            self.current = None;
+            return None;
+        }
+        self.current = Some(match (self.current, older) {
+            (None, _) => len - 1,
+            (Some(i), true) => (i + len - 1) % len,
+            (Some(i), false) => (i + 1) % len,
+        });
+        self.current_match()
+    }
+
+    /// Selects the newest match at or above `row`, or the oldest one if there is none.
+    pub fn select_before(&mut self, row: StableRowIndex) -> Option<&SearchMatch> {
+        self.current =
+            self.matches
+                .iter()
+                .rposition(|it| it.row <= row)
+                .or(if self.matches.is_empty() {
+                    None
+                } else {
+                    Some(0)
+                });
+        self.current_match()
+    }
+
+    /// Highlights the matches in a rendered row, the current one in a different color.
+    pub fn highlight(&self, line: &mut Line, row: StableRowIndex) {
+        let current = self.current_match();
+        let ranges = line_matches(&self.regex, line);
+        let cells = line.cells_mut();
+        for range in ranges {
+            let is_current = current
+                .map(|m| m.row == row && m.cells == range)
+                .unwrap_or(false);
+            let end = range.end.min(cells.len());
+            for cell in cells[range.start.min(end)..end].iter_mut() {
+                let attrs = cell.attrs_mut();
+                if is_current {
+                    attrs.set_background(ColorAttribute::from(AnsiColor::Yellow));
+                    attrs.set_foreground(ColorAttribute::from(AnsiColor::Black));
+                } else {
+                    attrs.set_reverse(true);
+                }
+            }
+        }
+    }
+}
+
+/// Finds the matches of `regex` in a row, as cell index ranges.
+pub fn line_matches(regex: &Regex, line: &Line) -> Vec<Range<usize>> {
+    let mut text = String::new();
+    // Cell range covered by each byte of `text`.
+    let mut cell_of_byte = Vec::<Range<usize>>::new();
+    for cell in line.visible_cells() {
+        let start = text.len();
+        text.push_str(cell.str());
+        let cells = cell.cell_index()..cell.cell_index() + cell.width().max(1);
+        cell_of_byte.extend((start..text.len()).map(|_| cells.clone()));
+    }
+
+    regex
+        .find_iter(&text)
+        .filter(|m| !m.as_str().is_empty())
+        .map(|m| cell_of_byte[m.start()].start..cell_of_byte[m.end() - 1].end)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(row: StableRowIndex) -> SearchMatch {
+        SearchMatch { row, cells: 0..1 }
+    }
+
+    #[test]
+    fn steps_through_matches_wrapping_around() {
+        let mut search = Search::new(Regex::new("x").unwrap());
+        search.update(0..0, vec![at(1), at(5), at(9)], 10);
+        let mut rows = Vec::new();
+        for older in [true, true, true, true, false] {
+            rows.push(search.step(older).map(|it| it.row));
+        }
+        assert_eq!(rows, vec![Some(9), Some(5), Some(1), Some(9), Some(1)]);
+    }
+
+    #[test]
+    fn keeps_unchanged_rows_and_the_current_match() {
+        let mut search = Search::new(Regex::new("x").unwrap());
+        search.update(0..0, vec![at(1), at(5), at(9)], 8);
+        search.select_before(5);
+        // Row 1 scrolled out of the history and rows from 8 on were searched again.
+        search.update(2..8, vec![at(9), at(12)], 10);
+        assert_eq!(search.matches, vec![at(5), at(9), at(12)]);
+        assert_eq!(search.current_match(), Some(&at(5)));
+        assert_eq!(search.scanned, Some(10));
+    }
+}